@@ -0,0 +1,141 @@
+use vm::audio::mixer::Channel;
+use vm::region::{Region, VideoStandard};
+use vm::video::model::Model;
+
+pub struct Options {
+    pub rom: String,
+    pub model: Model,
+    pub frames: u32,
+    pub screenshot: Option<String>,
+    pub dump_vdp: Option<String>,
+    pub wav: Option<String>,
+    pub fm: bool,
+    pub mute: Vec<Channel>,
+    pub solo: Vec<Channel>,
+    pub wav_channels: Option<String>,
+    pub vgm: Option<String>,
+    pub region: Option<Region>,
+    pub video: Option<VideoStandard>,
+    pub record_movie: Option<String>,
+    pub play_movie: Option<String>,
+    pub input: Option<String>,
+    pub load_state: Option<String>,
+    pub save_state: Option<String>,
+    pub print_hashes: bool,
+}
+
+pub const USAGE: &str =
+    "usage: rusty_sms [--model sms1|sms2|gg] [--region jp|us|eu] [--video ntsc|pal] [--frames N] [--screenshot FILE.png|FILE.ppm] [--dump-vdp DIR] [--wav FILE] [--wav-channels DIR] [--fm] [--mute CH,..] [--solo CH,..] [--vgm FILE] [--record-movie FILE] [--play-movie FILE] [--input SCRIPT] [--load-state FILE] [--save-state FILE] [--print-hashes] ROM|FILE.vgm";
+
+impl Options {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+        let mut rom = None;
+        let mut model = Model::MasterSystem2;
+        let mut frames = 60;
+        let mut screenshot = None;
+        let mut dump_vdp = None;
+        let mut wav = None;
+        let mut fm = false;
+        let mut mute = Vec::new();
+        let mut solo = Vec::new();
+        let mut wav_channels = None;
+        let mut vgm = None;
+        let mut region = None;
+        let mut video = None;
+        let mut record_movie = None;
+        let mut play_movie = None;
+        let mut input = None;
+        let mut load_state = None;
+        let mut save_state = None;
+        let mut print_hashes = false;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--model" => {
+                    model = match value(&mut args, &arg)?.as_str() {
+                        "sms1" => Model::MasterSystem1,
+                        "sms2" => Model::MasterSystem2,
+                        "gg" => Model::GameGear,
+                        other => return Err(format!("unknown model '{}'", other)),
+                    }
+                }
+                "--region" => {
+                    region = Some(match value(&mut args, &arg)?.as_str() {
+                        "jp" => Region::Japan,
+                        "us" => Region::Usa,
+                        "eu" => Region::Europe,
+                        other => return Err(format!("unknown region '{}'", other)),
+                    })
+                }
+                "--video" => {
+                    video = Some(match value(&mut args, &arg)?.as_str() {
+                        "ntsc" => VideoStandard::Ntsc,
+                        "pal" => VideoStandard::Pal,
+                        other => return Err(format!("unknown video standard '{}'", other)),
+                    })
+                }
+                "--frames" => frames = number(&mut args, &arg)?,
+                "--screenshot" => screenshot = Some(value(&mut args, &arg)?),
+                "--dump-vdp" => dump_vdp = Some(value(&mut args, &arg)?),
+                "--wav" => wav = Some(value(&mut args, &arg)?),
+                "--fm" => fm = true,
+                "--mute" => mute.extend(channels(&mut args, &arg)?),
+                "--solo" => solo.extend(channels(&mut args, &arg)?),
+                "--wav-channels" => wav_channels = Some(value(&mut args, &arg)?),
+                "--vgm" => vgm = Some(value(&mut args, &arg)?),
+                "--record-movie" => record_movie = Some(value(&mut args, &arg)?),
+                "--play-movie" => play_movie = Some(value(&mut args, &arg)?),
+                "--input" => input = Some(value(&mut args, &arg)?),
+                "--load-state" => load_state = Some(value(&mut args, &arg)?),
+                "--save-state" => save_state = Some(value(&mut args, &arg)?),
+                "--print-hashes" => print_hashes = true,
+                _ if arg.starts_with("--") => return Err(format!("unknown option '{}'", arg)),
+                _ => rom = Some(arg),
+            }
+        }
+
+        Ok(Options {
+            rom: rom.ok_or_else(|| "no ROM given".to_string())?,
+            model,
+            frames,
+            screenshot,
+            dump_vdp,
+            wav,
+            fm,
+            mute,
+            solo,
+            wav_channels,
+            vgm,
+            region,
+            video,
+            record_movie,
+            play_movie,
+            input,
+            load_state,
+            save_state,
+            print_hashes,
+        })
+    }
+}
+
+fn value<I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("missing value for '{}'", option))
+}
+
+fn number<I: Iterator<Item = String>>(args: &mut I, option: &str) -> Result<u32, String> {
+    let text = value(args, option)?;
+    text.parse()
+        .map_err(|_| format!("invalid number '{}' for '{}'", text, option))
+}
+
+// A comma-separated list such as "psg0,psg3,fm8".
+fn channels<I: Iterator<Item = String>>(
+    args: &mut I,
+    option: &str,
+) -> Result<Vec<Channel>, String> {
+    value(args, option)?
+        .split(',')
+        .map(|name| Channel::from_name(name).ok_or_else(|| format!("unknown channel '{}'", name)))
+        .collect()
+}
@@ -2,8 +2,162 @@
 
 extern crate num;
 
+mod cli;
 mod program;
 mod tests;
 mod vm;
 
-fn main() {}
+use cli::Options;
+use program::Program;
+use std::env;
+use std::fs;
+use std::process;
+use vm::audio::vgm::VgmPlayer;
+use vm::machine::Machine;
+use vm::movie::Movie;
+use vm::region;
+use vm::script::InputScript;
+
+fn main() {
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n{}", message, cli::USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(message) = run(&options) {
+        eprintln!("{}", message);
+        process::exit(1);
+    }
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let rom = fs::read(&options.rom).map_err(|e| format!("{}: {}", options.rom, e))?;
+    let movie = match options.play_movie {
+        Some(ref path) => Some(Movie::load(path).map_err(|e| format!("{}: {}", path, e))?),
+        None => None,
+    };
+    let mut vm;
+    let mut frames = options.frames;
+    match movie {
+        // A movie only replays correctly on the console it was recorded on.
+        Some(ref movie) => {
+            vm = Machine::with_model(movie.model);
+            vm.set_region(movie.region);
+            vm.set_video_standard(movie.video_standard);
+            if movie.fm {
+                vm.attach_fm_unit();
+            }
+            frames = movie.frames.len() as u32;
+        }
+        None => {
+            vm = Machine::with_model(options.model);
+            if let Some(region) = options.region.or_else(|| region::detect(&rom)) {
+                vm.set_region(region);
+            }
+            if let Some(standard) = options.video {
+                vm.set_video_standard(standard);
+            }
+            if options.fm {
+                vm.attach_fm_unit();
+            }
+        }
+    }
+    let mut player = None;
+    if options.rom.ends_with(".vgm") {
+        let vgm = VgmPlayer::new(rom).map_err(|e| format!("{}: {}", options.rom, e))?;
+        if vgm.uses_fm() {
+            vm.attach_fm_unit();
+            vm.write_port(0xF2, 0x03);
+        }
+        player = Some(vgm);
+    } else {
+        if !vm.load(&Program::from_bytes(rom)) {
+            return Err(format!("{}: ROM does not fit in memory", options.rom));
+        }
+        vm.cpu.goto(0);
+        vm.cpu.unhalt();
+    }
+    if let Some(ref path) = options.load_state {
+        let state = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        vm.load_state(&state)
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    if let Some(ref path) = options.input {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let script = InputScript::parse(&text).map_err(|e| format!("{}: {}", path, e))?;
+        // Run long enough for the last event to happen, even past --frames.
+        if let Some(last) = script.last_frame() {
+            frames = frames.max(last + 1);
+        }
+        vm.play_script(script);
+    }
+    if let (Some(movie), Some(path)) = (movie, options.play_movie.as_ref()) {
+        vm.play_movie(movie)
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    if options.record_movie.is_some() {
+        vm.start_movie_recording();
+    }
+
+    for &channel in &options.mute {
+        vm.set_channel_muted(channel, true);
+    }
+    for &channel in &options.solo {
+        vm.set_channel_solo(channel, true);
+    }
+    if let Some(ref path) = options.wav {
+        vm.start_recording(path)
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    if let Some(ref directory) = options.wav_channels {
+        vm.start_channel_recording(directory)
+            .map_err(|e| format!("{}: {}", directory, e))?;
+    }
+    if let Some(ref path) = options.vgm {
+        vm.start_vgm_logging(path)
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    match player {
+        Some(ref mut player) => {
+            while !player.is_finished() {
+                vm.play_vgm_frame(player);
+            }
+        }
+        None => {
+            for frame in 0..frames {
+                vm.run_frame();
+                if options.print_hashes {
+                    println!("{} {:016x}", frame, vm.state_hash());
+                }
+            }
+        }
+    }
+    if let Some(ref path) = options.vgm {
+        vm.stop_vgm_logging()
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    if let Some(ref path) = options.record_movie {
+        if let Some(movie) = vm.stop_movie_recording() {
+            movie.save(path).map_err(|e| format!("{}: {}", path, e))?;
+        }
+    }
+    if options.wav.is_some() || options.wav_channels.is_some() {
+        vm.stop_recording()
+            .map_err(|e| format!("audio recording: {}", e))?;
+    }
+
+    if let Some(ref path) = options.save_state {
+        fs::write(path, vm.save_state()).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    if let Some(ref path) = options.screenshot {
+        vm.save_screenshot(path)
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    if let Some(ref directory) = options.dump_vdp {
+        vm.dump_graphics(directory)
+            .map_err(|e| format!("{}: {}", directory, e))?;
+    }
+    Ok(())
+}
@@ -9,6 +9,10 @@ impl Program {
         Program { bin: Vec::new() }
     }
 
+    pub fn from_bytes(bin: Vec<u8>) -> Program {
+        Program { bin }
+    }
+
     pub fn raw(&self) -> &Vec<u8> {
         &self.bin
     }
@@ -1,11 +1,42 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
+    use cli::Options;
     use program::Program;
+    use std::cell::RefCell;
+    use std::env;
+    use std::fs;
+    use std::io::Cursor;
+    use std::rc::Rc;
+    use std::thread;
+    use vm::audio::blip::BlipBuffer;
+    use vm::audio::mixer;
+    use vm::audio::mixer::{Channel, Mixer};
+    use vm::audio::psg::Psg;
+    use vm::audio::ring;
+    use vm::audio::vgm::{VgmCommand, VgmPlayer, VgmWriter};
+    use vm::audio::wav::WavWriter;
+    use vm::audio::ym2413::Ym2413;
     use vm::cpu::alu;
     use vm::cpu::flags::Flag;
     use vm::cpu::registers::Registers;
     use vm::instructions::opcodes::Opcode;
+    use vm::io::controller::Buttons;
+    use vm::io::controller::{Paddle, SportsPad};
+    use vm::io::controller::{Peripheral, Phaser};
     use vm::machine::Machine;
+    use vm::movie::{FrameInput, Movie};
+    use vm::region;
+    use vm::region::{Region, VideoStandard};
+    use vm::rewind::RewindBuffer;
+    use vm::run::{StopCondition, StopReason};
+    use vm::savestate;
+    use vm::script::{Action, Button, InputScript};
+    use vm::video::frame::Frame;
+    use vm::video::image;
+    use vm::video::model::Model;
+    use vm::video::vdp;
+    use vm::video::vdp::Vdp;
 
     fn new_vm(regs: fn(&mut Registers), stream: Vec<Opcode>, start: u16) -> Machine {
         let mut vm = Machine::new();
@@ -152,6 +183,17 @@ mod tests {
         assert_eq!(vm.cpu.get_register(|regs| regs.c), 0xFE);
     }
 
+    #[test]
+    fn parity() {
+        for value in 0..=0xFF {
+            let mut vm = new_vm(|_| {}, vec![Opcode::XorB, Opcode::Halt], 0);
+            vm.cpu.state.registers.b = value;
+            vm.start();
+            let parity = Flag::ParityOverflow.get(&vm.cpu.state.status);
+            assert_eq!(parity, value.count_ones() % 2 == 0, "{:02X}", value);
+        }
+    }
+
     fn jump_test_flag(opcode: Opcode, param: u16, flag: Flag, flag_value: bool, expected: u16) {
         let mut vm = Machine::new();
         let mut p = Program::new();
@@ -213,4 +255,1667 @@ mod tests {
 
         assert_eq!(vm.cpu.state.registers.b, 42);
     }
+
+    fn write_vdp_register(vdp: &mut Vdp, index: u8, value: u8) {
+        vdp.write_control(value);
+        vdp.write_control(0x80 | index);
+    }
+
+    fn write_vdp_memory(vdp: &mut Vdp, code: u8, address: u16, bytes: &[u8]) {
+        vdp.write_control(address as u8);
+        vdp.write_control((code << 6) | (address >> 8) as u8);
+        for byte in bytes {
+            vdp.write_data(*byte);
+        }
+    }
+
+    #[test]
+    fn game_gear_cram_latch() {
+        let mut vm = Machine::with_model(Model::GameGear);
+        let mut p = Program::new();
+        p.add_param(Opcode::LdAX, 0x02);
+        p.add_param(Opcode::OutVXA, 0xBF);
+        p.add_param(Opcode::LdAX, 0xC0);
+        p.add_param(Opcode::OutVXA, 0xBF);
+        p.add_param(Opcode::LdAX, 0x5A);
+        p.add_param(Opcode::OutVXA, 0xBE);
+        p.add(Opcode::Halt);
+        p.add_param(Opcode::LdAX, 0xFF);
+        p.add_param(Opcode::OutVXA, 0xBE);
+        p.add(Opcode::Halt);
+        vm.load(&p);
+
+        vm.start();
+        assert_eq!(vm.vdp.cram().len(), 64);
+        assert_eq!(vm.vdp.cram()[2], 0x00);
+
+        vm.start_at(vm.cpu.state.program_counter);
+        assert_eq!(vm.vdp.cram()[2], 0x5A);
+        assert_eq!(vm.vdp.cram()[3], 0x0F);
+    }
+
+    #[test]
+    fn game_gear_compatibility_palette() {
+        let mut vdp = Vdp::new(Model::GameGear);
+        vdp.set_compatibility_mode(true);
+        write_vdp_memory(&mut vdp, 3, 0x01, &[0x3F, 0x06]);
+        assert_eq!(&vdp.cram()[2..6], &[0xFF, 0x0F, 0x5A, 0x00]);
+    }
+
+    #[test]
+    fn viewport() {
+        assert_eq!(Vdp::new(Model::MasterSystem2).frame().width, 256);
+        assert_eq!(Vdp::new(Model::MasterSystem2).frame().height, 192);
+
+        let mut vdp = Vdp::new(Model::GameGear);
+        assert_eq!(vdp.frame().width, 160);
+        assert_eq!(vdp.frame().height, 144);
+
+        vdp.set_compatibility_mode(true);
+        assert_eq!(vdp.frame().width, 256);
+    }
+
+    #[test]
+    fn render_background_tile() {
+        for model in &[Model::MasterSystem1, Model::MasterSystem2, Model::GameGear] {
+            let mut vdp = Vdp::new(*model);
+            write_vdp_register(&mut vdp, 1, 0x40);
+            write_vdp_register(&mut vdp, 2, 0x0E);
+            // Tile 1, row 0: leftmost pixel colour 1.
+            write_vdp_memory(&mut vdp, 1, 0x0020, &[0x80, 0x00, 0x00, 0x00]);
+            // Name table entry (7, 6) uses tile 1.
+            write_vdp_memory(&mut vdp, 1, 0x3800 + 6 * 64 + 7 * 2, &[0x01, 0x00]);
+            match *model {
+                Model::GameGear => write_vdp_memory(&mut vdp, 3, 0x02, &[0x0F, 0x00]),
+                _ => write_vdp_memory(&mut vdp, 3, 0x01, &[0x03]),
+            }
+            while !vdp.step_line() {}
+
+            let frame = vdp.frame();
+            let viewport = vdp.viewport();
+            assert_eq!(
+                frame.rgb(56 - viewport.x, 48 - viewport.y),
+                (0xFF, 0x00, 0x00)
+            );
+            assert_eq!(
+                frame.rgb(57 - viewport.x, 48 - viewport.y),
+                (0x00, 0x00, 0x00)
+            );
+        }
+    }
+
+    fn render_frame(vdp: &mut Vdp) {
+        while !vdp.step_line() {}
+    }
+
+    #[test]
+    fn extended_height() {
+        for &(model, expected) in &[
+            (Model::MasterSystem1, (192, 192)),
+            (Model::MasterSystem2, (224, 240)),
+            (Model::GameGear, (224, 240)),
+        ] {
+            let mut vdp = Vdp::new(model);
+            write_vdp_register(&mut vdp, 0, 0x06);
+            write_vdp_register(&mut vdp, 1, 0x10);
+            assert_eq!(vdp.active_height(), expected.0, "{:?}", model);
+            write_vdp_register(&mut vdp, 1, 0x08);
+            assert_eq!(vdp.active_height(), expected.1, "{:?}", model);
+        }
+    }
+
+    #[test]
+    fn name_table_mask() {
+        for &(model, mirrored) in &[(Model::MasterSystem1, true), (Model::MasterSystem2, false)] {
+            let mut vdp = Vdp::new(model);
+            write_vdp_register(&mut vdp, 1, 0x40);
+            write_vdp_register(&mut vdp, 2, 0x0E);
+            write_vdp_memory(&mut vdp, 1, 0x0020, &[0x80, 0x00, 0x00, 0x00]);
+            write_vdp_memory(&mut vdp, 1, 0x3800, &[0x01, 0x00]);
+            write_vdp_memory(&mut vdp, 3, 0x01, &[0x03]);
+            render_frame(&mut vdp);
+            assert_eq!(vdp.frame().rgb(0, 0), (0xFF, 0x00, 0x00));
+            assert_eq!(vdp.frame().rgb(0, 128) == (0xFF, 0x00, 0x00), mirrored);
+
+            write_vdp_register(&mut vdp, 2, 0x0F);
+            render_frame(&mut vdp);
+            assert_eq!(vdp.frame().rgb(0, 128), (0x00, 0x00, 0x00));
+        }
+    }
+
+    #[test]
+    fn sprite_zoom_limit() {
+        for &(model, zoomed) in &[(Model::MasterSystem1, false), (Model::MasterSystem2, true)] {
+            let mut vdp = Vdp::new(model);
+            write_vdp_register(&mut vdp, 1, 0x41);
+            write_vdp_register(&mut vdp, 5, 0xFF);
+            let pattern: Vec<u8> = (0..8).flat_map(|_| vec![0xFF, 0x00, 0x00, 0x00]).collect();
+            write_vdp_memory(&mut vdp, 1, 0x0020, &pattern);
+            write_vdp_memory(&mut vdp, 1, 0x3F00, &[9, 9, 9, 9, 9, 0xD0]);
+            write_vdp_memory(&mut vdp, 1, 0x3F80, &[0, 1, 20, 1, 40, 1, 60, 1, 80, 1]);
+            write_vdp_memory(&mut vdp, 3, 0x11, &[0x30]);
+            render_frame(&mut vdp);
+
+            let frame = vdp.frame();
+            assert_eq!(frame.rgb(60 + 12, 10), (0x00, 0x00, 0xFF));
+            assert_eq!(frame.rgb(80 + 12, 10) == (0x00, 0x00, 0xFF), zoomed);
+        }
+    }
+
+    #[test]
+    fn run_frame() {
+        let mut vm = Machine::new();
+        let mut p = Program::new();
+        p.add_param_word(Opcode::JpXX, 0x0000);
+        vm.load(&p);
+        vm.cpu.unhalt();
+
+        vm.run_frame();
+        assert_eq!(vm.vdp.line(), 0);
+        let cycles = vdp::CYCLES_PER_LINE * vdp::LINES_PER_FRAME as u64;
+        assert!(vm.cpu.cycles() >= cycles && vm.cpu.cycles() < cycles + 10);
+        assert!(!vm.cpu.is_halted());
+    }
+
+    #[test]
+    fn screenshot_encoding() {
+        let mut frame = Frame::new(2, 1);
+        frame.pixels = vec![0x00FF_8000, 0x0000_00FF];
+
+        let mut ppm = Vec::new();
+        image::write_ppm(&frame, &mut ppm).unwrap();
+        assert_eq!(ppm, b"P6\n2 1\n255\n\xFF\x80\x00\x00\x00\xFF".to_vec());
+
+        let mut png = Vec::new();
+        image::write_png(&frame, &mut png).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1A\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xAE\x42\x60\x82");
+    }
+
+    #[test]
+    fn command_line() {
+        let args = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let options = Options::parse(
+            args(&[
+                "--model",
+                "gg",
+                "--frames",
+                "5",
+                "--screenshot",
+                "a.png",
+                "--fm",
+                "--mute",
+                "psg0,fm1",
+                "rom.gg",
+            ])
+            .into_iter(),
+        )
+        .unwrap();
+        assert_eq!(options.rom, "rom.gg");
+        assert_eq!(options.model, Model::GameGear);
+        assert_eq!(options.frames, 5);
+        assert_eq!(options.screenshot, Some("a.png".to_string()));
+        assert!(options.fm);
+        assert_eq!(options.mute, vec![Channel::Psg(0), Channel::Fm(1)]);
+        assert!(Options::parse(args(&["--solo", "psg9", "rom"]).into_iter()).is_err());
+
+        assert!(Options::parse(args(&["--frames", "x", "rom"]).into_iter()).is_err());
+        assert!(Options::parse(args(&[]).into_iter()).is_err());
+    }
+
+    #[test]
+    fn graphics_debugging() {
+        let mut vdp = Vdp::new(Model::MasterSystem2);
+        write_vdp_register(&mut vdp, 2, 0xFF);
+        write_vdp_register(&mut vdp, 5, 0xFF);
+        write_vdp_memory(&mut vdp, 1, 33 * 32, &[0x01, 0x00, 0x00, 0x00]);
+        write_vdp_memory(&mut vdp, 1, 0x3800 + 27 * 64 + 31 * 2, &[0x21, 0x08]);
+        write_vdp_memory(&mut vdp, 1, 0x3F00, &[0x10, 0x20, 0xD0]);
+        write_vdp_memory(&mut vdp, 1, 0x3F80, &[0x30, 0x21, 0x40, 0x00]);
+        write_vdp_memory(&mut vdp, 3, 0x01, &[0x03]);
+        write_vdp_memory(&mut vdp, 3, 0x11, &[0x0C]);
+
+        let tiles = vdp.tile_sheet(0);
+        assert_eq!((tiles.width, tiles.height), (256, 128));
+        assert_eq!(tiles.rgb(15, 8), (0xFF, 0x00, 0x00));
+        assert_eq!(vdp.tile_sheet(1).rgb(15, 8), (0x00, 0xFF, 0x00));
+
+        let map = vdp.name_table_map();
+        assert_eq!((map.width, map.height), (256, 224));
+        assert_eq!(map.rgb(255, 216), (0x00, 0xFF, 0x00));
+
+        let sprites = vdp.sprite_table();
+        assert_eq!(sprites.len(), 2);
+        assert_eq!(sprites[0].pattern, 0x21);
+        assert_eq!(sprites[0].to_string(), " 0: x= 48 y= 16 pattern= 33");
+        assert_eq!(vdp.sprite_map().rgb(48 + 7, 17), (0x00, 0xFF, 0x00));
+
+        let swatches = vdp.palette_swatches();
+        assert_eq!((swatches.width, swatches.height), (256, 32));
+        assert_eq!(swatches.rgb(16, 0), (0xFF, 0x00, 0x00));
+        assert_eq!(swatches.rgb(16, 16), (0x00, 0xFF, 0x00));
+    }
+
+    #[test]
+    fn scanline_hook() {
+        let mut vm = Machine::new();
+        let mut p = Program::new();
+        p.add_param(Opcode::LdAX, 0x09);
+        p.add_param(Opcode::OutVXA, 0xBF);
+        p.add_param(Opcode::LdAX, 0x8A);
+        p.add_param(Opcode::OutVXA, 0xBF);
+        p.add(Opcode::Halt);
+        vm.load(&p);
+        vm.cpu.unhalt();
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let log = events.clone();
+        vm.on_scanline(move |event| {
+            log.borrow_mut().push((
+                event.line,
+                event.pixels.len(),
+                event.registers[10],
+                event.line_interrupt,
+                event.frame_interrupt,
+            ));
+        });
+        vm.run_frame();
+
+        let events = events.borrow();
+        assert_eq!(events.len(), vdp::LINES_PER_FRAME as usize);
+        assert_eq!(events[0], (0, 256, 9, true, false));
+        assert_eq!(events[9], (9, 256, 9, false, false));
+        assert_eq!(events[10], (10, 256, 9, true, false));
+        assert_eq!(events[192], (192, 0, 9, false, true));
+        let interrupts = events.iter().filter(|e| e.3).count();
+        assert_eq!(interrupts, 20);
+    }
+
+    #[test]
+    fn psg_latch_and_data() {
+        let mut vm = Machine::new();
+        let mut p = Program::new();
+        for value in &[0x8E, 0x0F, 0xC3, 0x9F, 0x03, 0xE5] {
+            p.add_param(Opcode::LdAX, *value);
+            p.add_param(Opcode::OutVXA, 0x7F);
+        }
+        p.add(Opcode::Halt);
+        vm.load(&p);
+        vm.start();
+
+        assert_eq!(vm.psg.tone(0), 0x0FE);
+        assert_eq!(vm.psg.tone(2), 0x003);
+        assert_eq!(vm.psg.attenuation(0), 0x03);
+        assert_eq!(vm.psg.attenuation(1), 0x0F);
+        assert_eq!(vm.psg.noise(), 0x05);
+    }
+
+    #[test]
+    fn psg_square_wave() {
+        let mut psg = Psg::new();
+        psg.write(0x84);
+        psg.write(0x00);
+        psg.write(0x90);
+        let mut outputs = Vec::new();
+        for tick in 1..=16 {
+            psg.run_until(tick * 16);
+            outputs.push(psg.channel_output(0) > 0);
+        }
+        let edges = outputs.windows(2).filter(|w| w[0] != w[1]).count();
+        assert_eq!(edges, 3);
+        assert_eq!(psg.channel_output(1), 0);
+    }
+
+    #[test]
+    fn psg_periodic_noise() {
+        let mut psg = Psg::new();
+        psg.write(0xE0);
+        psg.write(0xF0);
+        let mut highs = 0;
+        // Rate 0 shifts the register every 32 ticks.
+        for shift in 1..=64 {
+            psg.run_until(shift * 32 * 16);
+            if psg.channel_output(3) > 0 {
+                highs += 1;
+            }
+        }
+        assert_eq!(highs, 4);
+    }
+
+    #[test]
+    fn psg_sample_rate() {
+        let mut vm = Machine::new();
+        vm.run_frame();
+        let samples = vm.take_samples().len();
+        assert!(samples == 735 || samples == 736, "{}", samples);
+        assert!(vm.take_samples().is_empty());
+
+        vm.set_sample_rate(22_050);
+        vm.run_frame();
+        let samples = vm.take_samples().len();
+        assert!(samples == 367 || samples == 368, "{}", samples);
+    }
+
+    #[test]
+    fn wav_writer() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 22_050, 1).unwrap();
+        wav.write_samples(&[1, -2]).unwrap();
+        wav.write_samples(&[0x1234]).unwrap();
+        let bytes = wav.finish().unwrap().into_inner();
+
+        assert_eq!(bytes.len(), 44 + 6);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(&bytes[4..8], &42u32.to_le_bytes());
+        assert_eq!(&bytes[22..24], &1u16.to_le_bytes());
+        assert_eq!(&bytes[24..28], &22_050u32.to_le_bytes());
+        assert_eq!(&bytes[34..36], &16u16.to_le_bytes());
+        assert_eq!(&bytes[40..44], &6u32.to_le_bytes());
+        assert_eq!(&bytes[44..], &[0x01, 0x00, 0xFE, 0xFF, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn record_audio() {
+        let path = env::temp_dir().join("rusty_sms_record_audio.wav");
+        let mut vm = Machine::new();
+        vm.start_recording(&path).unwrap();
+        vm.run_frame();
+        vm.run_frame();
+        let samples = vm.take_samples().len();
+        vm.stop_recording().unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(bytes.len(), 44 + samples * 2);
+    }
+
+    #[test]
+    fn game_gear_stereo() {
+        let mut vm = Machine::with_model(Model::GameGear);
+        let mut p = Program::new();
+        for &(port, value) in &[(0x7F, 0x90), (0x7F, 0xBF), (0x7F, 0xDF), (0x7F, 0xFF)] {
+            p.add_param(Opcode::LdAX, value);
+            p.add_param(Opcode::OutVXA, port);
+        }
+        p.add_param(Opcode::LdAX, 0x10);
+        p.add_param(Opcode::OutVXA, 0x06);
+        p.add(Opcode::Halt);
+        vm.load(&p);
+        vm.cpu.unhalt();
+        vm.run_frame();
+
+        assert_eq!(vm.psg.panning(), 0x10);
+        assert_eq!(vm.psg.stereo_output(), (8191, 0));
+        let samples = vm.take_samples();
+        assert!(samples.len() == 1470 || samples.len() == 1472);
+        assert_eq!(&samples[samples.len() - 2..], &[8191, 0]);
+
+        let mut vm = Machine::new();
+        vm.load(&p);
+        vm.cpu.unhalt();
+        vm.run_frame();
+        assert_eq!(vm.psg.panning(), 0xFF);
+        assert_eq!(vm.take_samples().last(), Some(&8191));
+    }
+
+    #[test]
+    fn fm_ports() {
+        let mut p = Program::new();
+        for &(port, value) in &[(0xF2, 0x01), (0xF0, 0x10), (0xF1, 0xAB)] {
+            p.add_param(Opcode::LdAX, value);
+            p.add_param(Opcode::OutVXA, port);
+        }
+        p.add_param(Opcode::InAVX, 0xF2);
+        p.add(Opcode::Halt);
+
+        let mut vm = Machine::new();
+        vm.attach_fm_unit();
+        vm.load(&p);
+        vm.cpu.unhalt();
+        vm.run_frame();
+        assert_eq!(vm.cpu.state.registers.a, 0x01);
+        let fm = vm.fm.as_ref().unwrap();
+        assert_eq!(fm.register(0x10), 0xAB);
+        assert!(fm.fm_enabled());
+        assert!(!fm.psg_enabled());
+
+        let mut vm = Machine::new();
+        vm.load(&p);
+        vm.cpu.unhalt();
+        vm.run_frame();
+        assert_ne!(vm.cpu.state.registers.a, 0x01);
+    }
+
+    #[test]
+    fn fm_key_on() {
+        let mut fm = Ym2413::new();
+        fm.write_control(0x01);
+        for &(register, value) in &[(0x10, 0xAC), (0x30, 0x10), (0x20, 0x14)] {
+            fm.write_address(register);
+            fm.write_data(value);
+        }
+        let mut peak = 0;
+        for step in 1..2000 {
+            fm.run_until(step * 72);
+            peak = peak.max(fm.output().abs());
+        }
+        assert!(peak > 0);
+
+        fm.write_address(0x20);
+        fm.write_data(0x04);
+        fm.run_until(4_000_000);
+        assert_eq!(fm.output(), 0);
+    }
+
+    #[test]
+    fn fm_rhythm_mode() {
+        let mut fm = Ym2413::new();
+        fm.write_control(0x01);
+        for &(register, value) in &[(0x16, 0x20), (0x26, 0x05), (0x36, 0x00), (0x0E, 0x30)] {
+            fm.write_address(register);
+            fm.write_data(value);
+        }
+        assert!(fm.rhythm_mode());
+        let mut peak = 0;
+        for step in 1..2000 {
+            fm.run_until(step * 72);
+            peak = peak.max(fm.output().abs());
+        }
+        assert!(peak > 0);
+    }
+
+    #[test]
+    fn audio_ring_buffer() {
+        let (mut producer, mut consumer) = ring::ring_buffer(4);
+        assert_eq!(producer.push(&[1, 2, 3]), 3);
+        let mut out = [0; 2];
+        assert_eq!(consumer.pop(&mut out), 2);
+        assert_eq!(out, [1, 2]);
+        assert_eq!(producer.push(&[4, 5, 6, 7]), 3);
+        let mut out = [0; 8];
+        assert_eq!(consumer.pop(&mut out), 4);
+        assert_eq!(&out[..4], &[3, 4, 5, 6]);
+        assert!(consumer.is_empty());
+
+        let (mut producer, mut consumer) = ring::ring_buffer(64);
+        let writer = thread::spawn(move || {
+            let mut next = 0;
+            while next < 1000 {
+                next += producer.push(&[next as i16]) as i32;
+            }
+        });
+        let mut received = Vec::new();
+        while received.len() < 1000 {
+            let mut out = [0; 16];
+            let count = consumer.pop(&mut out);
+            received.extend_from_slice(&out[..count]);
+        }
+        writer.join().unwrap();
+        assert!(received.iter().enumerate().all(|(i, &s)| s == i as i16));
+    }
+
+    #[test]
+    fn band_limited_step() {
+        let mut blip = BlipBuffer::new(3_579_545, 44_100);
+        blip.add_delta(1000, 5000);
+        blip.end_frame(59_736);
+        let samples = blip.read_samples();
+        assert_eq!(samples.len(), 735);
+        assert_eq!(samples[0], 0);
+        assert!(samples[30..].iter().all(|&s| s == 5000));
+        // The step rises over several samples instead of jumping.
+        assert!(samples[..30].iter().any(|&s| s > 0 && s < 5000));
+
+        blip.end_frame(59_736);
+        assert_eq!(blip.samples_available(), 736);
+    }
+
+    #[test]
+    fn audio_stays_in_step_with_video() {
+        let mut vm = Machine::new();
+        let mut total = 0;
+        for _ in 0..60 {
+            vm.run_frame();
+            total += vm.take_samples().len();
+        }
+        assert_eq!(total, 60 * 59_736 * 44_100 / 3_579_545);
+    }
+
+    #[test]
+    fn take_audio() {
+        let mut vm = Machine::with_model(Model::GameGear);
+        vm.run_frame();
+        let mut out = [0; 101];
+        assert_eq!(vm.take_audio(&mut out), 100);
+        let reader = vm.detach_audio().unwrap();
+        assert_eq!(vm.take_audio(&mut out), 0);
+        assert_eq!(reader.len() % 2, 0);
+        assert!(reader.len() >= 1370);
+    }
+
+    #[test]
+    fn ultrasonic_tone_does_not_alias() {
+        let mut vm = Machine::new();
+        let mut p = Program::new();
+        for &value in &[0x82, 0x00, 0x90] {
+            p.add_param(Opcode::LdAX, value);
+            p.add_param(Opcode::OutVXA, 0x7F);
+        }
+        p.add(Opcode::Halt);
+        vm.load(&p);
+        vm.cpu.unhalt();
+        vm.run_frame();
+        let samples = vm.take_samples();
+        // A 56kHz square wave point-sampled at 44.1kHz would swing over the full range.
+        assert!(samples[100..].iter().all(|&s| s.abs() < 1000));
+    }
+
+    #[test]
+    fn audio_across_frame_boundary() {
+        // The padding moves which instruction runs past the end of the frame, and by how much.
+        for padding in 0..40 {
+            let mut p = Program::new();
+            for _ in 0..padding {
+                p.add(Opcode::Nop);
+            }
+            p.add_param_word(Opcode::LdVXXA, 0xC000);
+            p.add_param_word(Opcode::JpXX, 0x0000);
+            let mut vm = Machine::new();
+            vm.load(&p);
+            vm.cpu.unhalt();
+            for _ in 0..4 {
+                assert!(!vm.run_frame().audio.is_empty());
+            }
+        }
+    }
+
+    fn two_tone_machine() -> Machine {
+        let mut p = Program::new();
+        for &value in &[0x90, 0xB1] {
+            p.add_param(Opcode::LdAX, value);
+            p.add_param(Opcode::OutVXA, 0x7F);
+        }
+        p.add(Opcode::Halt);
+        let mut vm = Machine::new();
+        vm.load(&p);
+        vm.cpu.unhalt();
+        vm
+    }
+
+    #[test]
+    fn channel_mute_and_solo() {
+        let last_sample = |vm: &mut Machine| {
+            vm.run_frame();
+            *vm.take_samples().last().unwrap()
+        };
+        let mut vm = two_tone_machine();
+        assert_eq!(last_sample(&mut vm), 8191 + 6506);
+        vm.set_channel_muted(Channel::Psg(0), true);
+        assert_eq!(last_sample(&mut vm), 6506);
+        vm.set_channel_solo(Channel::Psg(0), true);
+        assert_eq!(last_sample(&mut vm), 8191);
+        vm.set_channel_solo(Channel::Psg(0), false);
+        vm.set_channel_muted(Channel::Psg(0), false);
+        assert_eq!(last_sample(&mut vm), 8191 + 6506);
+        vm.set_channel_solo(Channel::Psg(4), true);
+        vm.set_channel_muted(Channel::Fm(9), true);
+        assert_eq!(last_sample(&mut vm), 8191 + 6506);
+
+        assert_eq!(Channel::from_name("psg3"), Some(Channel::Psg(3)));
+        assert_eq!(Channel::from_name("fm8"), Some(Channel::Fm(8)));
+        assert_eq!(Channel::from_name("psg4"), None);
+        assert_eq!(Channel::Fm(2).to_string(), "fm2");
+
+        let mut mixer = Mixer::new(44_100, false);
+        mixer.split_channels(&[Channel::Psg(4), Channel::Psg(0), Channel::Fm(9)]);
+        mixer.run_until(10_000, &mut Psg::new(), &mut None);
+        mixer.end_frame(10_000);
+        let dumps = mixer.take_channel_samples();
+        assert_eq!(dumps.len(), 1);
+        assert_eq!(dumps[0].0, Channel::Psg(0));
+    }
+
+    #[test]
+    fn channel_recording() {
+        let directory = env::temp_dir().join("rusty_sms_channel_recording");
+        let mut vm = two_tone_machine();
+        vm.attach_fm_unit();
+        vm.set_channel_muted(Channel::Psg(0), true);
+        vm.start_channel_recording(&directory).unwrap();
+        vm.run_frame();
+        vm.run_frame();
+        vm.stop_recording().unwrap();
+
+        let read = |name: &str| fs::read(directory.join(name)).unwrap();
+        let psg0 = read("psg0.wav");
+        let fm8 = read("fm8.wav");
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(psg0.len(), fm8.len());
+        assert!(psg0.len() > 44 + 2 * 1400);
+        assert_eq!(&psg0[psg0.len() - 2..], &8191i16.to_le_bytes());
+        assert!(fm8[44..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn stop_recording_finishes_every_file() {
+        let directory = env::temp_dir().join("rusty_sms_stop_recording");
+        let mut vm = two_tone_machine();
+        vm.start_recording("/dev/full").unwrap();
+        vm.start_channel_recording(&directory).unwrap();
+        vm.run_frame();
+        assert!(vm.stop_recording().is_err());
+
+        let psg0 = fs::read(directory.join("psg0.wav")).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        assert!(psg0.len() > 44);
+        assert_eq!(&psg0[40..44], &(psg0.len() as u32 - 44).to_le_bytes());
+    }
+
+    #[test]
+    fn vgm_writer() {
+        let mut vgm = VgmWriter::new(Vec::new(), VideoStandard::Ntsc, 1000, true);
+        vgm.log(1000, VgmCommand::Psg(0x9F));
+        vgm.log(1000 + 59_736, VgmCommand::Fm(0x30, 0x10));
+        vgm.log(1000 + 59_736 + 406, VgmCommand::Psg(0x80));
+        let bytes = vgm.finish(1000 + 59_736 + 406).unwrap();
+
+        assert_eq!(&bytes[0..4], b"Vgm ");
+        assert_eq!(&bytes[0x08..0x0C], &[0x71, 0x01, 0, 0]);
+        assert_eq!(&bytes[0x10..0x14], &3_579_545u32.to_le_bytes());
+        assert_eq!(&bytes[0x18..0x1C], &740u32.to_le_bytes());
+        assert_eq!(&bytes[0x24..0x28], &60u32.to_le_bytes());
+        assert_eq!(&bytes[0x34..0x38], &0xCCu32.to_le_bytes());
+        assert_eq!(
+            &bytes[0x100..],
+            &[0x50, 0x9F, 0x62, 0x51, 0x30, 0x10, 0x74, 0x50, 0x80, 0x66]
+        );
+        assert_eq!(
+            bytes.len() - 4,
+            u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize
+        );
+    }
+
+    #[test]
+    fn vgm_log_and_play() {
+        let path = env::temp_dir().join("rusty_sms_vgm_log_and_play.vgm");
+        let mut vm = two_tone_machine();
+        vm.attach_fm_unit();
+        vm.start_vgm_logging(&path).unwrap();
+        vm.run_frame();
+        vm.write_port(0xF0, 0x10);
+        vm.write_port(0xF1, 0x55);
+        vm.run_frame();
+        vm.stop_vgm_logging().unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut player = VgmPlayer::new(bytes).unwrap();
+        assert!(player.uses_fm());
+        let mut replay = Machine::new();
+        replay.attach_fm_unit();
+        let mut frames = 0;
+        while !player.is_finished() {
+            replay.play_vgm_frame(&mut player);
+            frames += 1;
+        }
+        assert_eq!(frames, 2);
+        assert_eq!(replay.psg.attenuation(0), 0);
+        assert_eq!(replay.psg.attenuation(1), 1);
+        assert_eq!(replay.fm.as_ref().unwrap().register(0x10), 0x55);
+        assert!(VgmPlayer::new(vec![0; 0x40]).is_err());
+    }
+
+    #[test]
+    fn vgm_log_mid_run() {
+        let path = env::temp_dir().join("rusty_sms_vgm_log_mid_run.vgm");
+        let mut vm = two_tone_machine();
+        vm.attach_fm_unit();
+        vm.run_frame();
+        vm.write_port(0x7F, 0x8E);
+        vm.write_port(0x7F, 0x0F);
+        vm.write_port(0xF0, 0x10);
+        vm.write_port(0xF1, 0x55);
+        vm.start_vgm_logging(&path).unwrap();
+        vm.run_frame();
+        vm.stop_vgm_logging().unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut player = VgmPlayer::new(bytes).unwrap();
+        let mut replay = Machine::new();
+        replay.attach_fm_unit();
+        while !player.is_finished() {
+            replay.play_vgm_frame(&mut player);
+        }
+        assert_eq!(replay.psg.tone(0), 0x0FE);
+        assert_eq!(replay.psg.attenuation(0), 0);
+        assert_eq!(replay.psg.attenuation(1), 1);
+        assert_eq!(replay.psg.attenuation(3), 0x0F);
+        assert_eq!(replay.fm.as_ref().unwrap().register(0x10), 0x55);
+    }
+
+    #[test]
+    fn joypads() {
+        let mut vm = Machine::new();
+        assert_eq!(vm.read_port(0xDC), 0xFF);
+        assert_eq!(vm.read_port(0xDD), 0xFF);
+
+        vm.set_buttons(
+            0,
+            Buttons {
+                up: true,
+                button1: true,
+                ..Buttons::default()
+            },
+        );
+        vm.set_buttons(
+            1,
+            Buttons {
+                down: true,
+                left: true,
+                button2: true,
+                ..Buttons::default()
+            },
+        );
+        let mut p = Program::new();
+        p.add_param(Opcode::InAVX, 0xDC);
+        p.add(Opcode::Halt);
+        vm.load(&p);
+        vm.start();
+        assert_eq!(vm.cpu.state.registers.a, 0x6E);
+        assert_eq!(vm.read_port(0xDD), 0xF6);
+        vm.set_reset_button(true);
+        assert_eq!(vm.read_port(0xDD), 0xE6);
+        vm.set_reset_button(false);
+
+        // With TR and TH as outputs the pins read back the driven levels.
+        vm.write_port(0x3F, 0x50);
+        assert_eq!(vm.controllers.io_control(), 0x50);
+        assert_eq!(vm.read_port(0xDD), 0x3E);
+        assert_eq!(vm.read_port(0xDC), 0x6E);
+    }
+
+    #[test]
+    fn pause_triggers_nmi() {
+        let mut p = Program::new();
+        p.add_param_word(Opcode::LdSPXX, 0xDFF0);
+        p.add(Opcode::Halt);
+        p.add(Opcode::Halt);
+        let mut handler = Program::new();
+        handler.add(Opcode::IncA);
+        handler.add_param(Opcode::ExtendedPrefix, 0x45);
+
+        let mut vm = Machine::new();
+        vm.load(&p);
+        vm.load_at(&handler, 0x0066);
+        vm.cpu.state.iff1 = true;
+        vm.cpu.unhalt();
+        vm.run_frame();
+        assert_eq!(vm.cpu.state.registers.a, 0);
+        assert_eq!(vm.cpu.state.program_counter, 0x0004);
+
+        vm.press_pause();
+        vm.press_pause();
+        vm.run_frame();
+        assert_eq!(vm.cpu.state.registers.a, 1);
+        assert_eq!(vm.cpu.state.program_counter, 0x0005);
+        assert_eq!(vm.cpu.get_register_pair(|regs| (regs.s, regs.p)), 0xDFF0);
+        assert!(vm.cpu.state.iff1);
+        assert!(!vm.cpu.is_nmi_pending());
+    }
+
+    #[test]
+    fn interrupt_modes() {
+        let mut p = Program::new();
+        p.add_param(Opcode::ExtendedPrefix, 0x46);
+        p.add_param(Opcode::ExtendedPrefix, 0x56);
+        p.add(Opcode::Halt);
+        let mut vm = Machine::new();
+        vm.load(&p);
+        vm.start();
+        assert_eq!(vm.cpu.state.program_counter, 0x0005);
+        assert_eq!(vm.cpu.cycles(), 20);
+    }
+
+    #[test]
+    #[should_panic(expected = "unsupported opcode ED B3 at 0002")]
+    fn unsupported_extended_opcode() {
+        let mut p = Program::new();
+        p.add(Opcode::Nop);
+        p.add(Opcode::Nop);
+        p.add_param(Opcode::ExtendedPrefix, 0xB3);
+        p.add(Opcode::Halt);
+        let mut vm = Machine::new();
+        vm.load(&p);
+        vm.start();
+    }
+
+    #[test]
+    fn call_and_return() {
+        let mut p = Program::new();
+        p.add_param_word(Opcode::LdSPXX, 0xDFF0);
+        p.add_param_word(Opcode::CallXX, 0x0100);
+        p.add(Opcode::Halt);
+        let mut routine = Program::new();
+        routine.add_param(Opcode::LdBX, 0x12);
+        routine.add_param(Opcode::LdCX, 0x34);
+        routine.add(Opcode::PushBC);
+        routine.add(Opcode::PopDE);
+        routine.add(Opcode::Ret);
+
+        let mut vm = Machine::new();
+        vm.load(&p);
+        vm.load_at(&routine, 0x0100);
+        vm.start();
+        assert_eq!(vm.cpu.state.program_counter, 0x0007);
+        assert_eq!(vm.cpu.get_register_pair(|regs| (regs.d, regs.e)), 0x1234);
+    }
+
+    #[test]
+    fn light_phaser() {
+        let mut p = Program::new();
+        p.add_param(Opcode::InAVX, 0xDD);
+        p.add_param(Opcode::AndX, 0x40);
+        p.add_param_word(Opcode::JpNZXX, 0x0000);
+        p.add_param(Opcode::InAVX, 0x7F);
+        p.add(Opcode::Halt);
+
+        let mut vm = Machine::new();
+        vm.load(&p);
+        write_vdp_memory(&mut vm.vdp, 3, 16, &[0x3F]);
+        let aim = Phaser {
+            x: 100,
+            y: 50,
+            trigger: false,
+        };
+        vm.connect(0, Peripheral::LightPhaser(aim));
+        vm.cpu.unhalt();
+        vm.run_frame();
+        assert!(vm.cpu.is_halted());
+        assert_eq!(vm.cpu.state.registers.a, 50);
+        assert_eq!(vm.read_port(0xDD) & 0x40, 0x40);
+        assert_eq!(vm.read_port(0xDC) & 0x10, 0x10);
+
+        vm.connect(
+            0,
+            Peripheral::LightPhaser(Phaser {
+                trigger: true,
+                ..aim
+            }),
+        );
+        assert_eq!(vm.read_port(0xDC) & 0x10, 0);
+        assert_eq!(vdp::h_counter_at(0x93 * 2 + 2), 0xE9);
+
+        // A dark screen never reaches the sensor.
+        let mut vm = Machine::new();
+        vm.load(&p);
+        vm.connect(0, Peripheral::LightPhaser(aim));
+        vm.cpu.unhalt();
+        vm.run_frame();
+        assert!(!vm.cpu.is_halted());
+    }
+
+    #[test]
+    fn light_phaser_latch() {
+        let mut p = Program::new();
+        p.add(Opcode::Halt);
+        let mut vm = Machine::new();
+        vm.load(&p);
+        vm.connect(
+            0,
+            Peripheral::LightPhaser(Phaser {
+                x: 100,
+                y: 50,
+                trigger: false,
+            }),
+        );
+        write_vdp_memory(&mut vm.vdp, 3, 16, &[0x3F]);
+        vm.run_frame();
+        assert_eq!(vm.vdp.read_h_counter(), vdp::h_counter_at(100));
+
+        // The aim row drawn bright last frame does not count once this frame draws it dark.
+        write_vdp_memory(&mut vm.vdp, 3, 16, &[0x00]);
+        vm.vdp.latch_h_counter(0);
+        vm.run_frame();
+        assert_eq!(vm.vdp.read_h_counter(), vdp::h_counter_at(0));
+
+        // TH only falls once while the beam crosses the sensor's lines.
+        write_vdp_memory(&mut vm.vdp, 3, 16, &[0x3F]);
+        vm.run_until(&[StopCondition::Predicate(Box::new(|vm| vm.vdp.line() == 52))]);
+        assert_eq!(vm.vdp.read_h_counter(), vdp::h_counter_at(100));
+        vm.vdp.latch_h_counter(0);
+        vm.run_frame();
+        assert_eq!(vm.vdp.read_h_counter(), vdp::h_counter_at(0));
+    }
+
+    #[test]
+    fn paddle() {
+        let mut vm = Machine::new();
+        vm.connect(
+            0,
+            Peripheral::Paddle(Paddle {
+                position: 0xA5,
+                button: true,
+            }),
+        );
+        vm.write_port(0x3F, 0xFD);
+        assert_eq!(vm.read_port(0xDC), 0xC5);
+        vm.write_port(0x3F, 0xDD);
+        assert_eq!(vm.read_port(0xDC), 0xEA);
+
+        vm.write_port(0x3F, 0xFF);
+        vm.controllers.set_japanese(true);
+        assert_eq!(vm.controllers.read_port_a(0) & 0x3F, 0x05);
+        assert_eq!(vm.controllers.read_port_a(448) & 0x3F, 0x2A);
+    }
+
+    #[test]
+    fn sports_pad() {
+        let mut vm = Machine::new();
+        let mut pad = SportsPad {
+            x: 0x12,
+            y: 0xF0,
+            button1: false,
+            button2: true,
+        };
+        vm.connect(0, Peripheral::SportsPad(pad));
+        let read_out = |vm: &mut Machine| {
+            let mut nibbles = Vec::new();
+            for &io in &[0xDD, 0xFD, 0xDD, 0xFD] {
+                vm.write_port(0x3F, io);
+                nibbles.push(vm.read_port(0xDC) & 0x3F);
+            }
+            nibbles
+        };
+        assert_eq!(read_out(&mut vm), vec![0x11, 0x12, 0x1F, 0x10]);
+        pad.x = 0x10;
+        vm.connect(0, Peripheral::SportsPad(pad));
+        assert_eq!(read_out(&mut vm), vec![0x1F, 0x1E, 0x10, 0x10]);
+
+        vm.controllers.set_japanese(true);
+        pad.y = 0xF5;
+        vm.connect(0, Peripheral::SportsPad(pad));
+        let nibbles: Vec<u8> = (0..4)
+            .map(|step| vm.controllers.read_port_a(448 * (step + 1)) & 0x3F)
+            .collect();
+        assert_eq!(nibbles, vec![0x30, 0x10, 0x30, 0x15]);
+    }
+
+    #[test]
+    fn region_detection() {
+        let mut rom = vec![0; 0x8000];
+        assert_eq!(region::detect(&rom), None);
+        rom[0x7FF0..0x7FF8].copy_from_slice(b"TMR SEGA");
+        rom[0x7FFF] = 0x4C;
+        assert_eq!(region::detect(&rom), Some(Region::Usa));
+        rom[0x7FFF] = 0x3C;
+        assert_eq!(region::detect(&rom), Some(Region::Japan));
+    }
+
+    #[test]
+    fn japanese_th_readback() {
+        let mut vm = Machine::new();
+        vm.write_port(0x3F, 0xF5);
+        assert_eq!(vm.read_port(0xDD) & 0xC0, 0xC0);
+        vm.write_port(0x3F, 0x55);
+        assert_eq!(vm.read_port(0xDD) & 0xC0, 0x00);
+
+        vm.set_region(Region::Japan);
+        assert!(vm.controllers.is_japanese());
+        vm.write_port(0x3F, 0xF5);
+        assert_eq!(vm.read_port(0xDD) & 0xC0, 0x00);
+        vm.write_port(0x3F, 0x55);
+        assert_eq!(vm.read_port(0xDD) & 0xC0, 0xC0);
+    }
+
+    #[test]
+    fn pal_timing() {
+        let mut vm = Machine::new();
+        vm.set_region(Region::Europe);
+        assert_eq!(vm.video_standard(), VideoStandard::Pal);
+        let mut v_counter = Vec::new();
+        for _ in 0..313 {
+            v_counter.push(vm.vdp.read_v_counter());
+            vm.vdp.step_line();
+        }
+        assert_eq!(vm.vdp.line(), 0);
+        assert_eq!(v_counter[0xF2], 0xF2);
+        assert_eq!(v_counter[0xF3], 0xBA);
+        assert_eq!(v_counter[312], 0xFF);
+
+        vm.run_frame();
+        assert_eq!(vm.cpu.cycles(), 313 * 228);
+        let samples = vm.take_samples().len();
+        assert_eq!(samples, 313 * 228 * 44_100 / 3_546_893);
+
+        let mut vm = Machine::with_model(Model::GameGear);
+        assert_eq!(vm.read_port(0x00), 0xC0);
+        vm.set_region(Region::Japan);
+        vm.set_video_standard(VideoStandard::Pal);
+        assert_eq!(vm.read_port(0x00), 0xA0);
+    }
+
+    fn movie_machine() -> Machine {
+        let mut p = Program::new();
+        p.add_param_word(Opcode::LdSPXX, 0xDFF0);
+        p.add(Opcode::Halt);
+        p.add_param_word(Opcode::JpXX, 0x0003);
+        let mut handler = Program::new();
+        handler.add_param(Opcode::InAVX, 0xDC);
+        handler.add(Opcode::LdBA);
+        handler.add(Opcode::IncC);
+        handler.add_param(Opcode::ExtendedPrefix, 0x45);
+
+        let mut vm = Machine::new();
+        vm.load(&p);
+        vm.load_at(&handler, 0x0066);
+        vm.cpu.goto(0);
+        vm.cpu.unhalt();
+        vm
+    }
+
+    #[test]
+    fn movie_file() {
+        let mut movie = Movie::new(
+            Model::GameGear,
+            Region::Japan,
+            VideoStandard::Pal,
+            true,
+            0x1234_5678,
+        );
+        movie.frames.push(FrameInput::default());
+        movie.frames.push(FrameInput {
+            pads: [
+                Buttons {
+                    up: true,
+                    button2: true,
+                    ..Buttons::default()
+                },
+                Buttons {
+                    right: true,
+                    ..Buttons::default()
+                },
+            ],
+            peripherals: [
+                Peripheral::Joypad,
+                Peripheral::Paddle(Paddle {
+                    position: 0x80,
+                    button: true,
+                }),
+            ],
+            reset: true,
+            pause: true,
+        });
+        let mut file = Vec::new();
+        movie.write(&mut file).unwrap();
+        assert_eq!(file.len(), 21 + (3 + 1 + 1) + (3 + 1 + 3));
+        assert_eq!(Movie::read(Cursor::new(&file)).unwrap(), movie);
+        file[0] = b'X';
+        assert!(Movie::read(Cursor::new(&file)).is_err());
+    }
+
+    #[test]
+    fn movie_playback() {
+        let mut vm = movie_machine();
+        vm.start_movie_recording();
+        vm.run_frame();
+        vm.set_buttons(
+            0,
+            Buttons {
+                left: true,
+                button1: true,
+                ..Buttons::default()
+            },
+        );
+        vm.press_pause();
+        vm.run_frame();
+        vm.set_buttons(0, Buttons::default());
+        vm.set_reset_button(true);
+        vm.run_frame();
+        vm.press_pause();
+        vm.run_frame();
+        let movie = vm.stop_movie_recording().unwrap();
+        assert_eq!(movie.frames.len(), 4);
+        assert_eq!(vm.cpu.state.registers.c, 2);
+        assert_eq!(vm.cpu.state.registers.b, 0xFF);
+
+        assert!(Machine::new().play_movie(movie.clone()).is_err());
+        let mut replay = movie_machine();
+        replay.play_movie(movie).unwrap();
+        for _ in 0..4 {
+            assert!(replay.is_playing_movie());
+            replay.run_frame();
+        }
+        assert_eq!(replay.cpu.state.registers.b, 0xFF);
+        assert_eq!(replay.cpu.state.registers.c, 2);
+        assert_eq!(replay.cpu.cycles(), vm.cpu.cycles());
+        assert!(replay.controllers.is_reset_pressed());
+        replay.run_frame();
+        assert!(!replay.is_playing_movie());
+    }
+
+    #[test]
+    fn movie_peripherals() {
+        let mut vm = movie_machine();
+        vm.start_movie_recording();
+        let mut hashes = Vec::new();
+        for frame in 0..6u8 {
+            vm.connect(
+                0,
+                Peripheral::Paddle(Paddle {
+                    position: frame * 0x11,
+                    button: frame % 2 == 0,
+                }),
+            );
+            vm.press_pause();
+            vm.run_frame();
+            hashes.push(vm.state_hash());
+        }
+        assert_eq!(vm.cpu.state.registers.b, 0xD5);
+        let movie = vm.stop_movie_recording().unwrap();
+
+        let mut replay = movie_machine();
+        replay.play_movie(movie).unwrap();
+        for &hash in &hashes {
+            replay.run_frame();
+            assert_eq!(replay.state_hash(), hash);
+        }
+    }
+
+    #[test]
+    fn input_script() {
+        let script = InputScript::parse(
+            "# menu\nframe 125: release 1; frame 120: press 1\n\nframe 3: press p2.up; frame 3: pause\nframe 4: press reset",
+        )
+        .unwrap();
+        assert_eq!(
+            script.events(),
+            &[
+                (3, Action::Press(1, Button::Up)),
+                (3, Action::Pause),
+                (4, Action::Reset(true)),
+                (120, Action::Press(0, Button::Button1)),
+                (125, Action::Release(0, Button::Button1)),
+            ]
+        );
+        assert_eq!(script.last_frame(), Some(125));
+        assert!(InputScript::parse("frame x: press 1").is_err());
+        assert!(InputScript::parse("frame 1: press 3").is_err());
+        assert_eq!(
+            InputScript::parse("frame 1: pause\nframe 2: hold 1"),
+            Err("line 2: unknown action in 'frame 2: hold 1'".to_string())
+        );
+    }
+
+    #[test]
+    fn scripted_input() {
+        let mut vm = movie_machine();
+        vm.play_script(InputScript::parse("frame 1: press left; frame 1: pause; frame 2: press 2; frame 3: release left; frame 3: pause").unwrap());
+        vm.run_frame();
+        assert_eq!(vm.cpu.state.registers.c, 0);
+        vm.run_frame();
+        assert_eq!(vm.cpu.state.registers.c, 1);
+        assert_eq!(vm.cpu.state.registers.b, 0xFB);
+        vm.run_frame();
+        assert_eq!(vm.read_port(0xDC), 0xDB);
+        vm.run_frame();
+        assert_eq!(vm.cpu.state.registers.c, 2);
+        assert_eq!(vm.cpu.state.registers.b, 0xDF);
+    }
+
+    fn frame_interrupt_machine(enable: Opcode) -> Machine {
+        let mut p = Program::new();
+        p.add_param_word(Opcode::LdSPXX, 0xDFF0);
+        for &value in &[0x60, 0x81] {
+            p.add_param(Opcode::LdAX, value);
+            p.add_param(Opcode::OutVXA, 0xBF);
+        }
+        p.add(enable);
+        p.add(Opcode::Halt);
+        p.add_param_word(Opcode::JpXX, 0x000C);
+        let mut handler = Program::new();
+        handler.add(Opcode::IncC);
+        handler.add_param(Opcode::InAVX, 0xBF);
+        handler.add(Opcode::Ei);
+        handler.add_param(Opcode::ExtendedPrefix, 0x4D);
+
+        let mut vm = Machine::new();
+        vm.load(&p);
+        vm.load_at(&handler, 0x0038);
+        vm.cpu.goto(0);
+        vm.cpu.unhalt();
+        vm
+    }
+
+    #[test]
+    fn frame_interrupts() {
+        let mut vm = frame_interrupt_machine(Opcode::Ei);
+        for _ in 0..3 {
+            vm.run_frame();
+        }
+        assert_eq!(vm.cpu.state.registers.c, 3);
+        assert_eq!(vm.cpu.state.program_counter, 0x000D);
+        assert!(vm.cpu.state.iff1);
+        assert!(!vm.vdp.irq_pending());
+
+        let mut vm = frame_interrupt_machine(Opcode::Di);
+        for _ in 0..3 {
+            vm.run_frame();
+        }
+        assert_eq!(vm.cpu.state.registers.c, 0);
+        assert!(vm.vdp.irq_pending());
+    }
+
+    #[test]
+    fn run_frame_output() {
+        let mut vm = two_tone_machine();
+        let output = vm.run_frame();
+        assert_eq!((output.frame.width, output.frame.height), (256, 192));
+        let expected = mixer::DEFAULT_SAMPLE_RATE as usize / 60;
+        assert!(output.audio.len().abs_diff(expected) <= 1);
+        assert!(output.audio.iter().any(|&sample| sample != 0));
+        let mut ring = vec![0; output.audio.len()];
+        assert_eq!(vm.take_audio(&mut ring), output.audio.len());
+        assert_eq!(ring, output.audio);
+    }
+
+    #[test]
+    fn run_until() {
+        // 0: inc a; ld (0xC000),a; jp 0
+        let mut p = Program::new();
+        p.add(Opcode::IncA);
+        p.add_param_word(Opcode::LdVXXA, 0xC000);
+        p.add_param_word(Opcode::JpXX, 0x0000);
+        let mut vm = Machine::new();
+        vm.load(&p);
+        vm.cpu.goto(0);
+        vm.cpu.unhalt();
+
+        assert_eq!(
+            vm.run_until(&[StopCondition::Instructions(7)]),
+            StopReason::Instructions
+        );
+        assert_eq!(vm.cpu.state.registers.a, 3);
+        assert_eq!(vm.cpu.state.program_counter, 0x0001);
+        assert_eq!(
+            vm.run_until(&[StopCondition::Address(0x0004)]),
+            StopReason::Address(0x0004)
+        );
+        assert_eq!(vm.ram.read_u8(0xC000), 3);
+        assert_eq!(
+            vm.run_until(&[StopCondition::Memory(0xC000, 10), StopCondition::Halted]),
+            StopReason::Memory(0xC000, 10)
+        );
+        assert_eq!(vm.cpu.state.registers.a, 10);
+        assert_eq!(
+            vm.run_until(&[StopCondition::Predicate(Box::new(|vm| vm
+                .cpu
+                .state
+                .registers
+                .a
+                == 20))]),
+            StopReason::Predicate
+        );
+
+        let start = vm.cpu.cycles();
+        assert_eq!(
+            vm.run_until(&[StopCondition::Cycles(1000)]),
+            StopReason::Cycles
+        );
+        assert!((1000..1013).contains(&(vm.cpu.cycles() - start)));
+        assert_eq!(
+            vm.run_until(&[StopCondition::Frames(2), StopCondition::Cycles(10_000_000)]),
+            StopReason::Frames
+        );
+        assert_eq!(vm.vdp.line(), 0);
+        assert_eq!(
+            vm.run_until(&[StopCondition::Frames(1), StopCondition::Instructions(1)]),
+            StopReason::Instructions
+        );
+    }
+
+    #[test]
+    fn run_until_halt() {
+        let mut vm = frame_interrupt_machine(Opcode::Ei);
+        assert_eq!(vm.run_until(&[StopCondition::Halted]), StopReason::Halted);
+        assert_eq!(vm.cpu.state.program_counter, 0x000D);
+        assert_eq!(
+            vm.run_until(&[StopCondition::Address(0x0038)]),
+            StopReason::Address(0x0038)
+        );
+        assert_eq!(vm.vdp.line(), 193);
+        vm.run_frame();
+        assert_eq!(vm.vdp.line(), 0);
+        assert_eq!(vm.cpu.state.registers.c, 1);
+    }
+
+    #[test]
+    fn save_state() {
+        let mut vm = frame_interrupt_machine(Opcode::Ei);
+        vm.attach_fm_unit();
+        vm.write_port(0x7F, 0x8F);
+        vm.write_port(0x7F, 0x90);
+        vm.write_port(0xF0, 0x10);
+        vm.write_port(0xF1, 0x80);
+        vm.write_port(0xF0, 0x30);
+        vm.write_port(0xF1, 0x30);
+        vm.write_port(0xF0, 0x20);
+        vm.write_port(0xF1, 0x15);
+        vm.connect(
+            1,
+            Peripheral::Paddle(Paddle {
+                position: 0x42,
+                button: true,
+            }),
+        );
+        vm.run_frame();
+        vm.run_until(&[StopCondition::Cycles(5000)]);
+        let state = vm.save_state();
+
+        let run = |vm: &mut Machine| {
+            let outputs: Vec<_> = (0..3).map(|_| vm.run_frame()).collect();
+            (
+                outputs.last().unwrap().frame.pixels.clone(),
+                // The first frame after loading only has the audio from the load onwards.
+                outputs[1..].iter().map(|o| o.audio.len()).sum::<usize>(),
+                vm.cpu.cycles(),
+                vm.cpu.state.registers.c,
+                vm.save_state(),
+            )
+        };
+        let expected = run(&mut vm);
+        vm.set_buttons(0, Buttons::from_bits(0x3F));
+        vm.load_state(&state).unwrap();
+        assert_eq!(vm.controllers.buttons(0), Buttons::default());
+        assert_eq!(run(&mut vm), expected);
+
+        let mut other = frame_interrupt_machine(Opcode::Di);
+        other.load_state(&state).unwrap();
+        assert!(other.fm.is_some());
+        assert_eq!(run(&mut other), expected);
+    }
+
+    #[test]
+    fn invalid_save_state() {
+        let vm = frame_interrupt_machine(Opcode::Ei);
+        let state = vm.save_state();
+        let mut other = Machine::with_model(Model::GameGear);
+        assert!(other.load_state(&state).is_err());
+
+        let mut other = frame_interrupt_machine(Opcode::Di);
+        other.run_frame();
+        let before = other.save_state();
+        assert!(other.load_state(&state[..state.len() - 1]).is_err());
+        let mut extended = state.clone();
+        extended.push(0);
+        assert!(other.load_state(&extended).is_err());
+        let mut version = state.clone();
+        version[8] = savestate::VERSION + 1;
+        assert!(other.load_state(&version).is_err());
+        assert!(other.load_state(b"SMSMOVIE").is_err());
+        assert_eq!(other.save_state(), before);
+    }
+
+    #[test]
+    fn rewind_buffer() {
+        let state = |seed: u8, len: usize| (0..len).map(|i| (i as u8) ^ seed).collect::<Vec<u8>>();
+        let mut rewind = RewindBuffer::new(2, 3);
+        assert!(rewind.wants_snapshot(0));
+        assert!(!rewind.wants_snapshot(1));
+        rewind.push_snapshot(0, state(0, 1000));
+        assert!(!rewind.wants_snapshot(0));
+        rewind.push_snapshot(2, state(1, 1200));
+        rewind.push_snapshot(4, state(1, 900));
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(rewind.rewind_to(3), Some((2, state(1, 1200))));
+        assert_eq!(rewind.len(), 2);
+        rewind.push_snapshot(4, state(2, 1000));
+        rewind.push_snapshot(6, state(3, 1000));
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(rewind.oldest_frame(), Some(2));
+        assert_eq!(rewind.rewind_to(1), None);
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(rewind.rewind_to(2), Some((2, state(1, 1200))));
+        assert_eq!(rewind.len(), 1);
+    }
+
+    #[test]
+    fn rewind() {
+        let mut vm = movie_machine();
+        vm.enable_rewind(4, 8);
+        let mut states = vec![vm.save_state()];
+        for frame in 0..10u8 {
+            vm.set_buttons(0, Buttons::from_bits(frame));
+            if frame % 3 == 1 {
+                vm.press_pause();
+            }
+            vm.run_frame();
+            states.push(vm.save_state());
+        }
+        assert_eq!(vm.frame_count(), 10);
+        assert_eq!(vm.cpu.state.registers.c, 3);
+        assert_eq!(vm.rewind_buffer().unwrap().len(), 3);
+
+        for frame in (0..10).rev() {
+            assert!(vm.rewind_frame());
+            assert_eq!(vm.frame_count(), frame);
+            assert!(vm.save_state() == states[frame as usize]);
+        }
+        assert!(!vm.rewind_frame());
+
+        vm.run_frame();
+        vm.run_until(&[StopCondition::Cycles(1000)]);
+        assert!(vm.rewind_frame());
+        assert_eq!(vm.frame_count(), 1);
+        assert!(vm.save_state() == states[1]);
+    }
+
+    #[test]
+    fn rewind_peripherals() {
+        let mut vm = movie_machine();
+        vm.enable_rewind(4, 8);
+        let mut states = vec![vm.save_state()];
+        for frame in 0..6u8 {
+            vm.connect(
+                0,
+                Peripheral::Paddle(Paddle {
+                    position: frame * 0x11,
+                    button: frame % 2 == 0,
+                }),
+            );
+            vm.press_pause();
+            vm.run_frame();
+            states.push(vm.save_state());
+        }
+        assert_eq!(vm.cpu.state.registers.b, 0xD5);
+
+        for frame in (0..6).rev() {
+            assert!(vm.rewind_frame());
+            assert!(vm.save_state() == states[frame]);
+        }
+    }
+
+    #[test]
+    fn rewind_is_silent() {
+        let mut vm = colour_cycling_machine();
+        let lines = Rc::new(RefCell::new(0));
+        let counter = lines.clone();
+        vm.on_scanline(move |_| *counter.borrow_mut() += 1);
+        vm.enable_rewind(4, 8);
+        for _ in 0..7 {
+            vm.run_frame();
+        }
+        let seen = *lines.borrow();
+        assert!(vm.rewind_frame());
+        assert_eq!(vm.frame_count(), 6);
+        assert_eq!(*lines.borrow(), seen);
+        vm.run_frame();
+        assert_eq!(*lines.borrow(), seen + 262);
+    }
+
+    #[test]
+    fn rewind_after_load() {
+        let mut vm = colour_cycling_machine();
+        vm.enable_rewind(1, 30);
+        let mut states = vec![vm.save_state()];
+        for _ in 0..10 {
+            vm.run_frame();
+            states.push(vm.save_state());
+        }
+
+        vm.load_state(&states[3]).unwrap();
+        assert_eq!(vm.frame_count(), 3);
+        vm.run_frame();
+        vm.run_frame();
+        assert!(vm.rewind_frame());
+        assert_eq!(vm.frame_count(), 4);
+        assert!(vm.save_state() == states[4]);
+        assert!(vm.rewind_frame());
+        assert!(!vm.rewind_frame());
+
+        let snapshot = vm.snapshot();
+        vm.run_frame();
+        vm.run_frame();
+        vm.restore_snapshot(&snapshot);
+        assert_eq!(vm.frame_count(), 3);
+        vm.run_frame();
+        assert!(vm.rewind_frame());
+        assert!(vm.save_state() == states[3]);
+        assert!(!vm.rewind_frame());
+    }
+
+    #[test]
+    fn rewind_movie_recording() {
+        let mut vm = movie_machine();
+        vm.enable_rewind(2, 8);
+        vm.start_movie_recording();
+        let mut hashes = Vec::new();
+        let mut states = Vec::new();
+        let run = |vm: &mut Machine, hashes: &mut Vec<u64>, frame: u8| {
+            vm.connect(
+                0,
+                Peripheral::Paddle(Paddle {
+                    position: frame.wrapping_mul(0x37),
+                    button: frame & 1 != 0,
+                }),
+            );
+            vm.press_pause();
+            vm.run_frame();
+            hashes.push(vm.state_hash());
+        };
+        for frame in 0..6 {
+            states.push(vm.save_state());
+            run(&mut vm, &mut hashes, frame);
+        }
+        assert!(vm.rewind_frame());
+        assert!(vm.rewind_frame());
+        hashes.truncate(4);
+        for frame in 10..13 {
+            run(&mut vm, &mut hashes, frame);
+        }
+        vm.load_state(&states[2]).unwrap();
+        hashes.truncate(2);
+        for frame in 20..24 {
+            run(&mut vm, &mut hashes, frame);
+        }
+        let movie = vm.stop_movie_recording().unwrap();
+        assert_eq!(movie.frames.len(), 6);
+
+        let mut replay = movie_machine();
+        replay.play_movie(movie).unwrap();
+        for &hash in &hashes {
+            replay.run_frame();
+            assert_eq!(replay.state_hash(), hash);
+        }
+    }
+
+    #[test]
+    fn state_hash() {
+        let hashes = |pause_frame: u32| {
+            let mut vm = movie_machine();
+            vm.connect(
+                1,
+                Peripheral::SportsPad(SportsPad {
+                    x: 3,
+                    ..SportsPad::default()
+                }),
+            );
+            (0..6)
+                .map(|frame| {
+                    if frame == pause_frame {
+                        vm.press_pause();
+                    }
+                    vm.run_frame();
+                    vm.state_hash()
+                })
+                .collect::<Vec<_>>()
+        };
+        let first = hashes(2);
+        assert_eq!(hashes(2), first);
+        let other = hashes(3);
+        assert_eq!(other[..2], first[..2]);
+        assert_ne!(other[2], first[2]);
+
+        let mut vm = movie_machine();
+        vm.run_frame();
+        let state = vm.save_state();
+        let hash = vm.state_hash();
+        vm.run_frame();
+        assert_ne!(vm.state_hash(), hash);
+        vm.load_state(&state).unwrap();
+        assert_eq!(vm.state_hash(), hash);
+    }
+
+    fn colour_cycling_machine() -> Machine {
+        let mut vm = frame_interrupt_machine(Opcode::Ei);
+        let mut handler = Program::new();
+        handler.add(Opcode::IncC);
+        handler.add_param(Opcode::InAVX, 0xBF);
+        for &value in &[0x00, 0xC0] {
+            handler.add_param(Opcode::LdAX, value);
+            handler.add_param(Opcode::OutVXA, 0xBF);
+        }
+        handler.add(Opcode::LdAC);
+        handler.add_param(Opcode::OutVXA, 0xBE);
+        handler.add(Opcode::Ei);
+        handler.add_param(Opcode::ExtendedPrefix, 0x4D);
+        vm.load_at(&handler, 0x0038);
+        for &value in &[0x85, 0x01, 0x90] {
+            vm.write_port(0x7F, value);
+        }
+        vm
+    }
+
+    #[test]
+    fn run_ahead() {
+        let mut plain = colour_cycling_machine();
+        let expected: Vec<_> = (0..8)
+            .map(|_| (plain.run_frame(), plain.state_hash()))
+            .collect();
+
+        let mut vm = colour_cycling_machine();
+        let lines = Rc::new(RefCell::new(0));
+        let counter = lines.clone();
+        vm.on_scanline(move |_| *counter.borrow_mut() += 1);
+        vm.set_run_ahead(2);
+        for frame in 0..6 {
+            let output = vm.run_frame();
+            assert!(output.frame.pixels == expected[frame + 2].0.frame.pixels);
+            assert!(output.frame.pixels != expected[frame].0.frame.pixels);
+            assert_eq!(output.audio, expected[frame].0.audio);
+            assert_eq!(vm.state_hash(), expected[frame].1);
+            assert_eq!(vm.frame_count(), frame as u64 + 1);
+        }
+        assert_eq!(*lines.borrow(), 6 * vdp::LINES_PER_FRAME as usize);
+
+        let snapshot = vm.snapshot();
+        let hash = vm.state_hash();
+        vm.set_run_ahead(0);
+        vm.run_frame();
+        vm.restore_snapshot(&snapshot);
+        assert_eq!(vm.state_hash(), hash);
+        let audio = vm.run_frame().audio;
+        assert!(audio.len().abs_diff(expected[6].0.audio.len()) <= 1);
+    }
 }
@@ -0,0 +1,100 @@
+use std::f64::consts::PI;
+
+const PHASES: usize = 32;
+const WIDTH: usize = 16;
+const KERNEL_BITS: u32 = 15;
+// Fraction of the output Nyquist frequency kept by the low-pass kernel.
+const CUTOFF: f64 = 0.9;
+
+// Turns amplitude changes at chip clock times into band-limited samples at the output rate.
+// Positions are kept as clocks * sample_rate so the sample count never drifts from the clock.
+pub struct BlipBuffer {
+    clock_rate: u64,
+    sample_rate: u64,
+    origin: u64,
+    buffer: Vec<i64>,
+    integrator: i64,
+    kernel: Vec<[i64; WIDTH]>,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: u32, sample_rate: u32) -> BlipBuffer {
+        BlipBuffer {
+            clock_rate: clock_rate as u64,
+            sample_rate: sample_rate as u64,
+            origin: 0,
+            buffer: Vec::new(),
+            integrator: 0,
+            kernel: (0..PHASES).map(kernel).collect(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate as u32
+    }
+
+    // `time` is in clocks since the end of the previous frame.
+    pub fn add_delta(&mut self, time: u64, delta: i32) {
+        if delta == 0 {
+            return;
+        }
+        let position = self.origin + time * self.sample_rate;
+        let index = (position / self.clock_rate) as usize;
+        let phase = ((position % self.clock_rate) * PHASES as u64 / self.clock_rate) as usize;
+        if self.buffer.len() < index + WIDTH {
+            self.buffer.resize(index + WIDTH, 0);
+        }
+        for (slot, &tap) in self.buffer[index..].iter_mut().zip(&self.kernel[phase]) {
+            *slot += delta as i64 * tap;
+        }
+    }
+
+    pub fn end_frame(&mut self, time: u64) {
+        self.origin += time * self.sample_rate;
+    }
+
+    pub fn samples_available(&self) -> usize {
+        (self.origin / self.clock_rate) as usize
+    }
+
+    pub fn read_samples(&mut self) -> Vec<i16> {
+        let count = self.samples_available();
+        if self.buffer.len() < count {
+            self.buffer.resize(count, 0);
+        }
+        let mut samples = Vec::with_capacity(count);
+        for delta in self.buffer.drain(..count) {
+            self.integrator += delta;
+            let sample = self.integrator >> KERNEL_BITS;
+            samples.push(sample.max(i16::MIN as i64).min(i16::MAX as i64) as i16);
+        }
+        self.origin -= count as u64 * self.clock_rate;
+        samples
+    }
+}
+
+// A windowed sinc for an impulse `phase / PHASES` of a sample late, summing to exactly 1.0.
+fn kernel(phase: usize) -> [i64; WIDTH] {
+    let offset = (WIDTH / 2) as f64 + phase as f64 / PHASES as f64;
+    let mut taps = [0.0; WIDTH];
+    for (i, tap) in taps.iter_mut().enumerate() {
+        let x = i as f64 - offset;
+        let sinc = if x == 0.0 {
+            1.0
+        } else {
+            (PI * CUTOFF * x).sin() / (PI * CUTOFF * x)
+        };
+        let w = 2.0 * PI * (x / WIDTH as f64 + 0.5);
+        let window = (0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos()).max(0.0);
+        *tap = sinc * window;
+    }
+    let sum: f64 = taps.iter().sum();
+    let unit = (1i64 << KERNEL_BITS) as f64;
+    let mut kernel = [0; WIDTH];
+    for (scaled, tap) in kernel.iter_mut().zip(&taps) {
+        *scaled = (tap / sum * unit).round() as i64;
+    }
+    let error = (1i64 << KERNEL_BITS) - kernel.iter().sum::<i64>();
+    kernel[WIDTH / 2] += error;
+    kernel
+}
@@ -0,0 +1,296 @@
+use std::fmt;
+use vm::audio::blip::BlipBuffer;
+use vm::audio::psg::Psg;
+use vm::audio::ym2413;
+use vm::audio::ym2413::Ym2413;
+use vm::region::NTSC_CLOCK;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+pub const PSG_CHANNELS: usize = 4;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Channel {
+    // 0-2 are the tone channels, 3 the noise channel.
+    Psg(usize),
+    Fm(usize),
+}
+
+impl Channel {
+    pub fn psg_channels() -> Vec<Channel> {
+        (0..PSG_CHANNELS).map(Channel::Psg).collect()
+    }
+
+    pub fn fm_channels() -> Vec<Channel> {
+        (0..ym2413::CHANNELS).map(Channel::Fm).collect()
+    }
+
+    // Accepts the names printed by Display, e.g. "psg3" or "fm0".
+    pub fn from_name(name: &str) -> Option<Channel> {
+        let (constructor, index): (fn(usize) -> Channel, &str) =
+            if let Some(index) = name.strip_prefix("psg") {
+                (Channel::Psg, index)
+            } else if let Some(index) = name.strip_prefix("fm") {
+                (Channel::Fm, index)
+            } else {
+                return None;
+            };
+        index
+            .parse()
+            .ok()
+            .map(constructor)
+            .filter(|channel| channel.exists())
+    }
+
+    pub fn exists(self) -> bool {
+        match self {
+            Channel::Psg(index) => index < PSG_CHANNELS,
+            Channel::Fm(index) => index < ym2413::CHANNELS,
+        }
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Channel::Psg(index) => write!(f, "psg{}", index),
+            Channel::Fm(index) => write!(f, "fm{}", index),
+        }
+    }
+}
+
+struct ChannelDump {
+    channel: Channel,
+    buffer: BlipBuffer,
+    level: i32,
+    samples: Vec<i16>,
+}
+
+// Feeds every level change of the sound chips into band-limited buffers, one per output channel.
+// Stereo output interleaves left and right.
+pub struct Mixer {
+    clock: u32,
+    buffers: Vec<BlipBuffer>,
+    levels: [i32; 2],
+    frame_start: u64,
+    samples: Vec<i16>,
+    muted: Vec<Channel>,
+    soloed: Vec<Channel>,
+    dumps: Vec<ChannelDump>,
+}
+
+impl Mixer {
+    pub fn new(sample_rate: u32, stereo: bool) -> Mixer {
+        let channels = if stereo { 2 } else { 1 };
+        Mixer {
+            clock: NTSC_CLOCK,
+            buffers: (0..channels)
+                .map(|_| BlipBuffer::new(NTSC_CLOCK, sample_rate))
+                .collect(),
+            levels: [0; 2],
+            frame_start: 0,
+            samples: Vec::new(),
+            muted: Vec::new(),
+            soloed: Vec::new(),
+            dumps: Vec::new(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.buffers[0].sample_rate()
+    }
+
+    pub fn clock(&self) -> u32 {
+        self.clock
+    }
+
+    // The CPU clock the chips run from; PAL consoles are slightly slower.
+    pub fn set_clock(&mut self, clock: u32, cycle: u64) {
+        let sample_rate = self.sample_rate();
+        self.clock = clock;
+        self.set_sample_rate(sample_rate, cycle);
+    }
+
+    // Samples still pending in the old buffers are delivered first.
+    pub fn set_sample_rate(&mut self, sample_rate: u32, cycle: u64) {
+        self.end_frame(cycle);
+        let clock = self.clock;
+        for buffer in &mut self.buffers {
+            *buffer = BlipBuffer::new(clock, sample_rate);
+        }
+        self.levels = [0; 2];
+        for dump in &mut self.dumps {
+            dump.buffer = BlipBuffer::new(clock, sample_rate);
+            dump.level = 0;
+        }
+    }
+
+    // Drops pending output and starts a new frame at `cycle`, for when the machine jumps to a saved state.
+    pub fn restart(&mut self, cycle: u64) {
+        let (clock, sample_rate) = (self.clock, self.sample_rate());
+        for buffer in &mut self.buffers {
+            *buffer = BlipBuffer::new(clock, sample_rate);
+        }
+        self.levels = [0; 2];
+        for dump in &mut self.dumps {
+            dump.buffer = BlipBuffer::new(clock, sample_rate);
+            dump.level = 0;
+        }
+        self.frame_start = cycle;
+        self.samples.clear();
+    }
+
+    pub fn is_stereo(&self) -> bool {
+        self.buffers.len() == 2
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.buffers.len() as u16
+    }
+
+    // Channels the chips do not have are ignored here and in set_solo and split_channels.
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted.retain(|&c| c != channel);
+        if muted && channel.exists() {
+            self.muted.push(channel);
+        }
+    }
+
+    // While any channel is soloed only the soloed channels are heard.
+    pub fn set_solo(&mut self, channel: Channel, soloed: bool) {
+        self.soloed.retain(|&c| c != channel);
+        if soloed && channel.exists() {
+            self.soloed.push(channel);
+        }
+    }
+
+    pub fn is_audible(&self, channel: Channel) -> bool {
+        if self.soloed.is_empty() {
+            !self.muted.contains(&channel)
+        } else {
+            self.soloed.contains(&channel)
+        }
+    }
+
+    // Renders each listed channel on its own as well, ignoring mute and solo; an empty list stops.
+    pub fn split_channels(&mut self, channels: &[Channel]) {
+        let (clock, sample_rate) = (self.clock, self.sample_rate());
+        self.dumps = channels
+            .iter()
+            .filter(|channel| channel.exists())
+            .map(|&channel| ChannelDump {
+                channel,
+                buffer: BlipBuffer::new(clock, sample_rate),
+                level: 0,
+                samples: Vec::new(),
+            })
+            .collect();
+    }
+
+    pub fn run_until(&mut self, cycle: u64, psg: &mut Psg, fm: &mut Option<Ym2413>) {
+        loop {
+            let mut next = psg.next_tick();
+            if let Some(ref fm) = *fm {
+                next = next.min(fm.next_sample());
+            }
+            if next > cycle {
+                break;
+            }
+            psg.run_until(next);
+            if let Some(ref mut fm) = *fm {
+                fm.run_until(next);
+            }
+            self.update(next, psg, fm);
+        }
+    }
+
+    // Closes the current frame at `cycle` and makes its samples available.
+    pub fn end_frame(&mut self, cycle: u64) {
+        let time = cycle - self.frame_start;
+        self.frame_start = cycle;
+        for buffer in &mut self.buffers {
+            buffer.end_frame(time);
+        }
+        let channels: Vec<Vec<i16>> = self.buffers.iter_mut().map(|b| b.read_samples()).collect();
+        for i in 0..channels[0].len() {
+            for channel in &channels {
+                self.samples.push(channel[i]);
+            }
+        }
+        for dump in &mut self.dumps {
+            dump.buffer.end_frame(time);
+            let samples = dump.buffer.read_samples();
+            dump.samples.extend(samples);
+        }
+    }
+
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        let mut samples = Vec::new();
+        ::std::mem::swap(&mut samples, &mut self.samples);
+        samples
+    }
+
+    pub fn take_channel_samples(&mut self) -> Vec<(Channel, Vec<i16>)> {
+        self.dumps
+            .iter_mut()
+            .map(|dump| {
+                let mut samples = Vec::new();
+                ::std::mem::swap(&mut samples, &mut dump.samples);
+                (dump.channel, samples)
+            })
+            .collect()
+    }
+
+    fn update(&mut self, cycle: u64, psg: &Psg, fm: &Option<Ym2413>) {
+        let (psg_enabled, fm_enabled) = match *fm {
+            Some(ref fm) => (fm.psg_enabled(), fm.fm_enabled()),
+            None => (true, false),
+        };
+        let output = |channel: Channel| match channel {
+            Channel::Psg(index) if psg_enabled => psg.channel_output(index) as i32,
+            Channel::Fm(index) if fm_enabled => {
+                fm.as_ref().map_or(0, |fm| fm.channel_output(index))
+            }
+            _ => 0,
+        };
+
+        let mut levels = [0; 2];
+        for index in 0..PSG_CHANNELS {
+            let channel = Channel::Psg(index);
+            if !self.is_audible(channel) {
+                continue;
+            }
+            let level = output(channel);
+            if self.is_stereo() {
+                if psg.panning() & (0x10 << index) != 0 {
+                    levels[0] += level;
+                }
+                if psg.panning() & (0x01 << index) != 0 {
+                    levels[1] += level;
+                }
+            } else {
+                levels[0] += level;
+            }
+        }
+        if fm.is_some() {
+            for index in 0..ym2413::CHANNELS {
+                let channel = Channel::Fm(index);
+                if self.is_audible(channel) {
+                    let level = output(channel);
+                    levels[0] += level;
+                    levels[1] += level;
+                }
+            }
+        }
+
+        let time = cycle - self.frame_start;
+        for (i, buffer) in self.buffers.iter_mut().enumerate() {
+            buffer.add_delta(time, levels[i] - self.levels[i]);
+            self.levels[i] = levels[i];
+        }
+        for dump in &mut self.dumps {
+            let level = output(dump.channel);
+            dump.buffer.add_delta(time, level - dump.level);
+            dump.level = level;
+        }
+    }
+}
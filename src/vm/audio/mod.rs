@@ -0,0 +1,7 @@
+pub mod blip;
+pub mod mixer;
+pub mod psg;
+pub mod ring;
+pub mod vgm;
+pub mod wav;
+pub mod ym2413;
@@ -0,0 +1,223 @@
+use std::io;
+use vm::savestate::{StateReader, StateWriter};
+
+const CYCLES_PER_TICK: u64 = 16;
+const NOISE_CHANNEL: usize = 3;
+const LFSR_RESET: u16 = 0x8000;
+// The SMS variant of the chip taps bits 0 and 3 of a 16-bit register.
+const LFSR_TAPS: u16 = 0x0009;
+
+// Attenuation is 2dB per step; 15 is silence. Four channels at full volume fit in an i16.
+const VOLUMES: [i16; 16] = [
+    8191, 6506, 5168, 4105, 3261, 2590, 2057, 1634, 1298, 1031, 819, 650, 516, 410, 326, 0,
+];
+
+#[derive(Clone)]
+pub struct Psg {
+    tones: [u16; 3],
+    noise: u8,
+    attenuations: [u8; 4],
+    latched_channel: usize,
+    latched_volume: bool,
+    counters: [u16; 4],
+    outputs: [bool; 4],
+    lfsr: u16,
+    panning: u8,
+    cycle: u64,
+}
+
+impl Psg {
+    pub fn new() -> Psg {
+        Psg {
+            tones: [0; 3],
+            noise: 0,
+            attenuations: [0x0F; 4],
+            latched_channel: 0,
+            latched_volume: false,
+            counters: [0; 4],
+            outputs: [false; 4],
+            lfsr: LFSR_RESET,
+            panning: 0xFF,
+            cycle: 0,
+        }
+    }
+
+    // Game Gear port 0x06: bits 4-7 send channels 0-3 left, bits 0-3 send them right.
+    pub fn write_panning(&mut self, value: u8) {
+        self.panning = value;
+    }
+
+    pub fn panning(&self) -> u8 {
+        self.panning
+    }
+
+    pub fn tone(&self, channel: usize) -> u16 {
+        self.tones[channel]
+    }
+
+    pub fn noise(&self) -> u8 {
+        self.noise
+    }
+
+    pub fn attenuation(&self, channel: usize) -> u8 {
+        self.attenuations[channel]
+    }
+
+    // The writes that take a freshly reset chip to the current tone, noise and volume registers.
+    pub fn register_writes(&self) -> Vec<u8> {
+        let mut writes = Vec::new();
+        for (channel, &tone) in self.tones.iter().enumerate() {
+            writes.push(0x80 | (channel as u8) << 5 | (tone & 0x0F) as u8);
+            writes.push((tone >> 4) as u8 & 0x3F);
+        }
+        writes.push(0xE0 | self.noise);
+        for (channel, &attenuation) in self.attenuations.iter().enumerate() {
+            writes.push(0x90 | (channel as u8) << 5 | attenuation);
+        }
+        writes
+    }
+
+    pub fn write(&mut self, value: u8) {
+        if value & 0x80 != 0 {
+            self.latched_channel = ((value >> 5) & 0x03) as usize;
+            self.latched_volume = value & 0x10 != 0;
+            self.write_latched(value & 0x0F, false);
+        } else {
+            self.write_latched(value & 0x3F, true);
+        }
+    }
+
+    pub fn run_until(&mut self, cycle: u64) {
+        while self.cycle + CYCLES_PER_TICK <= cycle {
+            self.tick();
+            self.cycle += CYCLES_PER_TICK;
+        }
+    }
+
+    pub fn next_tick(&self) -> u64 {
+        self.cycle + CYCLES_PER_TICK
+    }
+
+    pub fn output(&self) -> i16 {
+        (0..4).map(|channel| self.channel_output(channel)).sum()
+    }
+
+    pub fn stereo_output(&self) -> (i16, i16) {
+        let mix = |shift: usize| {
+            (0..4)
+                .filter(|channel| self.panning & (1 << (channel + shift)) != 0)
+                .map(|channel| self.channel_output(channel))
+                .sum()
+        };
+        (mix(4), mix(0))
+    }
+
+    pub fn channel_output(&self, channel: usize) -> i16 {
+        let volume = VOLUMES[self.attenuations[channel] as usize];
+        let high = if channel == NOISE_CHANNEL {
+            self.lfsr & 1 != 0
+        } else {
+            // Periods of 0 and 1 hold the output high.
+            self.tones[channel] <= 1 || self.outputs[channel]
+        };
+        if high {
+            volume
+        } else {
+            -volume
+        }
+    }
+
+    fn write_latched(&mut self, data: u8, data_byte: bool) {
+        let channel = self.latched_channel;
+        if self.latched_volume {
+            self.attenuations[channel] = data & 0x0F;
+        } else if channel == NOISE_CHANNEL {
+            self.noise = data & 0x07;
+            self.lfsr = LFSR_RESET;
+        } else if data_byte {
+            self.tones[channel] = (self.tones[channel] & 0x000F) | ((data as u16) << 4);
+        } else {
+            self.tones[channel] = (self.tones[channel] & 0x03F0) | data as u16;
+        }
+    }
+
+    fn noise_period(&self) -> u16 {
+        match self.noise & 0x03 {
+            0 => 0x10,
+            1 => 0x20,
+            2 => 0x40,
+            _ => self.tones[2],
+        }
+    }
+
+    fn tick(&mut self) {
+        for channel in 0..3 {
+            if self.counters[channel] > 0 {
+                self.counters[channel] -= 1;
+            }
+            if self.counters[channel] == 0 {
+                self.counters[channel] = self.tones[channel];
+                self.outputs[channel] = !self.outputs[channel];
+            }
+        }
+
+        if self.counters[NOISE_CHANNEL] > 0 {
+            self.counters[NOISE_CHANNEL] -= 1;
+        }
+        if self.counters[NOISE_CHANNEL] == 0 {
+            self.counters[NOISE_CHANNEL] = self.noise_period();
+            self.outputs[NOISE_CHANNEL] = !self.outputs[NOISE_CHANNEL];
+            if self.outputs[NOISE_CHANNEL] {
+                self.shift_lfsr();
+            }
+        }
+    }
+
+    fn shift_lfsr(&mut self) {
+        let input = if self.noise & 0x04 != 0 {
+            (self.lfsr & LFSR_TAPS).count_ones() as u16 & 1
+        } else {
+            self.lfsr & 1
+        };
+        self.lfsr = (self.lfsr >> 1) | (input << 15);
+    }
+
+    pub(crate) fn save_state(&self, out: &mut StateWriter) {
+        for &tone in &self.tones {
+            out.write_u16(tone);
+        }
+        out.write_u8(self.noise);
+        out.write_bytes(&self.attenuations);
+        out.write_u8(self.latched_channel as u8);
+        out.write_bool(self.latched_volume);
+        for &counter in &self.counters {
+            out.write_u16(counter);
+        }
+        for &output in &self.outputs {
+            out.write_bool(output);
+        }
+        out.write_u16(self.lfsr);
+        out.write_u8(self.panning);
+        out.write_u64(self.cycle);
+    }
+
+    pub(crate) fn load_state(&mut self, input: &mut StateReader) -> io::Result<()> {
+        for tone in &mut self.tones {
+            *tone = input.read_u16()?;
+        }
+        self.noise = input.read_u8()?;
+        input.read_bytes(&mut self.attenuations)?;
+        self.latched_channel = (input.read_u8()? & 0x03) as usize;
+        self.latched_volume = input.read_bool()?;
+        for counter in &mut self.counters {
+            *counter = input.read_u16()?;
+        }
+        for output in &mut self.outputs {
+            *output = input.read_bool()?;
+        }
+        self.lfsr = input.read_u16()?;
+        self.panning = input.read_u8()?;
+        self.cycle = input.read_u64()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+use std::sync::atomic::{AtomicI16, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// Single-producer single-consumer sample queue; either end may live on another thread.
+// push and pop take `&mut self`, so each end can only be used from one place at a time.
+struct Shared {
+    slots: Vec<AtomicI16>,
+    written: AtomicUsize,
+    read: AtomicUsize,
+}
+
+pub struct Producer {
+    shared: Arc<Shared>,
+}
+
+pub struct Consumer {
+    shared: Arc<Shared>,
+}
+
+pub fn ring_buffer(capacity: usize) -> (Producer, Consumer) {
+    let shared = Arc::new(Shared {
+        slots: (0..capacity).map(|_| AtomicI16::new(0)).collect(),
+        written: AtomicUsize::new(0),
+        read: AtomicUsize::new(0),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer { shared },
+    )
+}
+
+impl Shared {
+    fn len(&self) -> usize {
+        let written = self.written.load(Ordering::Acquire);
+        written.wrapping_sub(self.read.load(Ordering::Acquire))
+    }
+}
+
+impl Producer {
+    pub fn capacity(&self) -> usize {
+        self.shared.slots.len()
+    }
+
+    pub fn free(&self) -> usize {
+        self.capacity() - self.shared.len()
+    }
+
+    // Returns how many samples fitted; the rest are dropped.
+    pub fn push(&mut self, samples: &[i16]) -> usize {
+        let slots = &self.shared.slots;
+        let written = self.shared.written.load(Ordering::Relaxed);
+        let count = samples.len().min(self.free());
+        for (i, &sample) in samples[..count].iter().enumerate() {
+            slots[written.wrapping_add(i) % slots.len()].store(sample, Ordering::Relaxed);
+        }
+        self.shared
+            .written
+            .store(written.wrapping_add(count), Ordering::Release);
+        count
+    }
+}
+
+impl Consumer {
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Returns how many samples were copied into `out`.
+    pub fn pop(&mut self, out: &mut [i16]) -> usize {
+        let slots = &self.shared.slots;
+        let read = self.shared.read.load(Ordering::Relaxed);
+        let count = out.len().min(self.len());
+        for (i, sample) in out[..count].iter_mut().enumerate() {
+            *sample = slots[read.wrapping_add(i) % slots.len()].load(Ordering::Relaxed);
+        }
+        self.shared
+            .read
+            .store(read.wrapping_add(count), Ordering::Release);
+        count
+    }
+}
@@ -0,0 +1,237 @@
+use std::io;
+use std::io::Write;
+use vm::region::VideoStandard;
+
+pub const SAMPLE_RATE: u64 = 44_100;
+const VERSION: u32 = 0x171;
+const HEADER_SIZE: usize = 0x100;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum VgmCommand {
+    Psg(u8),
+    GameGearStereo(u8),
+    Fm(u8, u8),
+    Wait(u32),
+    End,
+}
+
+// Commands are buffered until finish() so that chip writes never fail on I/O.
+pub struct VgmWriter<W: Write> {
+    out: W,
+    standard: VideoStandard,
+    clock: u32,
+    fm: bool,
+    start_cycle: u64,
+    start_sample: u64,
+    samples: u64,
+    data: Vec<u8>,
+}
+
+impl<W: Write> VgmWriter<W> {
+    pub fn new(out: W, standard: VideoStandard, start_cycle: u64, fm: bool) -> VgmWriter<W> {
+        VgmWriter {
+            out,
+            standard,
+            clock: standard.cpu_clock(),
+            fm,
+            start_cycle,
+            start_sample: 0,
+            samples: 0,
+            data: Vec::new(),
+        }
+    }
+
+    pub fn log(&mut self, cycle: u64, command: VgmCommand) {
+        self.wait_until(cycle);
+        match command {
+            VgmCommand::Psg(value) => self.data.extend_from_slice(&[0x50, value]),
+            VgmCommand::GameGearStereo(value) => self.data.extend_from_slice(&[0x4F, value]),
+            VgmCommand::Fm(register, value) => {
+                self.data.extend_from_slice(&[0x51, register, value])
+            }
+            VgmCommand::Wait(samples) => self.wait(samples as u64),
+            VgmCommand::End => {}
+        }
+    }
+
+    pub fn finish(mut self, cycle: u64) -> io::Result<W> {
+        self.wait_until(cycle);
+        self.data.push(0x66);
+
+        let mut header = vec![0; HEADER_SIZE];
+        let mut put = |offset: usize, value: u32| {
+            header[offset..offset + 4].copy_from_slice(&value.to_le_bytes())
+        };
+        put(0x00, u32::from_le_bytes(*b"Vgm "));
+        put(0x04, (HEADER_SIZE + self.data.len() - 4) as u32);
+        put(0x08, VERSION);
+        put(0x0C, self.clock);
+        put(0x10, if self.fm { self.clock } else { 0 });
+        put(0x18, self.samples as u32);
+        put(0x24, self.standard.frames_per_second());
+        put(0x34, (HEADER_SIZE - 0x34) as u32);
+        // SN76489 noise feedback pattern and shift register width.
+        header[0x28] = 0x09;
+        header[0x2A] = 16;
+
+        self.out.write_all(&header)?;
+        self.out.write_all(&self.data)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    // Carries on logging from `cycle` after the machine jumped to another point in time.
+    pub fn restart(&mut self, cycle: u64) {
+        self.start_cycle = cycle;
+        self.start_sample = self.samples;
+    }
+
+    fn wait_until(&mut self, cycle: u64) {
+        let target =
+            self.start_sample + (cycle - self.start_cycle) * SAMPLE_RATE / self.clock as u64;
+        if target > self.samples {
+            let samples = target - self.samples;
+            self.wait(samples);
+        }
+    }
+
+    fn wait(&mut self, mut samples: u64) {
+        self.samples += samples;
+        while samples > 0 {
+            let step = samples.min(0xFFFF);
+            match step {
+                735 => self.data.push(0x62),
+                882 => self.data.push(0x63),
+                1..=16 => self.data.push(0x70 + step as u8 - 1),
+                _ => {
+                    self.data.push(0x61);
+                    self.data.extend_from_slice(&(step as u16).to_le_bytes());
+                }
+            }
+            samples -= step;
+        }
+    }
+}
+
+// Plays a VGM file once, ignoring its loop point and commands for chips the SMS lacks.
+pub struct VgmPlayer {
+    data: Vec<u8>,
+    position: usize,
+    fm: bool,
+    origin: Option<u64>,
+    samples: u64,
+    finished: bool,
+}
+
+impl VgmPlayer {
+    pub fn new(data: Vec<u8>) -> Result<VgmPlayer, String> {
+        if data.len() < 0x40 || &data[0..4] != b"Vgm " {
+            return Err("not a VGM file".to_string());
+        }
+        let word = |offset: usize| {
+            if offset + 4 <= data.len() {
+                u32::from_le_bytes([
+                    data[offset],
+                    data[offset + 1],
+                    data[offset + 2],
+                    data[offset + 3],
+                ])
+            } else {
+                0
+            }
+        };
+        let version = word(0x08);
+        let position = if version >= 0x150 && word(0x34) != 0 {
+            0x34 + word(0x34) as usize
+        } else {
+            0x40
+        };
+        let fm = word(0x10) != 0;
+        Ok(VgmPlayer {
+            data,
+            position,
+            fm,
+            origin: None,
+            samples: 0,
+            finished: false,
+        })
+    }
+
+    pub fn uses_fm(&self) -> bool {
+        self.fm
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    // Returns the next chip write due before `end` with the cycle it happens at.
+    // Playback starts at the `now` given to the first call; `clock` is the CPU clock.
+    pub fn next_write(&mut self, clock: u32, now: u64, end: u64) -> Option<(u64, VgmCommand)> {
+        let origin = *self.origin.get_or_insert(now);
+        loop {
+            let cycle = origin + self.samples * clock as u64 / SAMPLE_RATE;
+            if self.finished || cycle >= end {
+                return None;
+            }
+            match self.read_command() {
+                VgmCommand::Wait(samples) => self.samples += samples as u64,
+                VgmCommand::End => self.finished = true,
+                command => return Some((cycle, command)),
+            }
+        }
+    }
+
+    fn read_command(&mut self) -> VgmCommand {
+        loop {
+            let byte = |offset: usize| self.data.get(self.position + offset).cloned();
+            let opcode = match byte(0) {
+                Some(opcode) => opcode,
+                None => return VgmCommand::End,
+            };
+            let (command, length) = match opcode {
+                0x4F => (byte(1).map(VgmCommand::GameGearStereo), 2),
+                0x50 => (byte(1).map(VgmCommand::Psg), 2),
+                0x51 => match (byte(1), byte(2)) {
+                    (Some(register), Some(value)) => (Some(VgmCommand::Fm(register, value)), 3),
+                    _ => (None, 3),
+                },
+                0x61 => match (byte(1), byte(2)) {
+                    (Some(low), Some(high)) => (
+                        Some(VgmCommand::Wait(u16::from_le_bytes([low, high]) as u32)),
+                        3,
+                    ),
+                    _ => (None, 3),
+                },
+                0x62 => (Some(VgmCommand::Wait(735)), 1),
+                0x63 => (Some(VgmCommand::Wait(882)), 1),
+                0x66 => (Some(VgmCommand::End), 1),
+                0x67 => {
+                    let size = (3..7)
+                        .map(|i| byte(i).unwrap_or(0) as usize)
+                        .rev()
+                        .fold(0, |size, b| (size << 8) | b);
+                    (None, 7 + size)
+                }
+                0x70..=0x7F => (Some(VgmCommand::Wait((opcode & 0x0F) as u32 + 1)), 1),
+                0x80..=0x8F => (Some(VgmCommand::Wait((opcode & 0x0F) as u32)), 1),
+                0x30..=0x3F => (None, 2),
+                0x40..=0x4E | 0x52..=0x5F | 0xA0..=0xBF => (None, 3),
+                0xC0..=0xDF => (None, 4),
+                0xE0..=0xFF => (None, 5),
+                0x90 | 0x91 | 0x95 => (None, 5),
+                0x92 => (None, 6),
+                0x93 => (None, 11),
+                0x94 => (None, 2),
+                _ => return VgmCommand::End,
+            };
+            self.position += length;
+            if self.position > self.data.len() {
+                return VgmCommand::End;
+            }
+            if let Some(command) = command {
+                return command;
+            }
+        }
+    }
+}
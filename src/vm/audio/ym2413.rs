@@ -0,0 +1,573 @@
+use std::f64::consts::PI;
+use std::io;
+use vm::savestate::{StateReader, StateWriter};
+
+const CYCLES_PER_SAMPLE: u64 = 72;
+pub const CHANNELS: usize = 9;
+const RHYTHM_CHANNEL: usize = 6;
+
+// Instrument 0 is the user patch in registers 0x00-0x07; 16-18 are the rhythm patches.
+const INSTRUMENTS: [[u8; 8]; 19] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x71, 0x61, 0x1E, 0x17, 0xD0, 0x78, 0x00, 0x17],
+    [0x13, 0x41, 0x1A, 0x0D, 0xD8, 0xF7, 0x23, 0x13],
+    [0x13, 0x01, 0x99, 0x00, 0xF2, 0xC4, 0x21, 0x23],
+    [0x11, 0x61, 0x0E, 0x07, 0x8D, 0x64, 0x70, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x31, 0x22, 0x16, 0x05, 0xE0, 0x71, 0x00, 0x18],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x33, 0x21, 0x2D, 0x13, 0xB0, 0x70, 0x00, 0x07],
+    [0x61, 0x61, 0x1B, 0x06, 0x64, 0x65, 0x10, 0x17],
+    [0x41, 0x61, 0x0B, 0x18, 0x85, 0xF0, 0x81, 0x07],
+    [0x33, 0x01, 0x83, 0x11, 0xEA, 0xEF, 0x10, 0x04],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x61, 0x50, 0x0C, 0x05, 0xD2, 0xF5, 0x40, 0x42],
+    [0x01, 0x01, 0x55, 0x03, 0xE9, 0x90, 0x03, 0x02],
+    [0x41, 0x41, 0x89, 0x03, 0xF1, 0xE4, 0xC0, 0x13],
+    [0x01, 0x01, 0x18, 0x0F, 0xDF, 0xF8, 0x6A, 0x6D],
+    [0x01, 0x01, 0x00, 0x00, 0xC8, 0xD8, 0xA7, 0x68],
+    [0x05, 0x01, 0x00, 0x00, 0xF8, 0xAA, 0x59, 0x55],
+];
+
+// Frequency multipliers, doubled so that 1/2 stays an integer.
+const MULTIPLIERS: [u32; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+
+// Key scale level for the top four F-number bits, in 0.375dB envelope units.
+const KEY_SCALE_LEVELS: [i32; 16] = [
+    0, 24, 32, 37, 40, 43, 45, 47, 48, 50, 51, 52, 53, 54, 55, 56,
+];
+
+const ENVELOPE_STEPS: [[u32; 8]; 4] = [
+    [0, 1, 0, 1, 0, 1, 0, 1],
+    [0, 1, 0, 1, 1, 1, 0, 1],
+    [0, 1, 1, 1, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 1],
+];
+
+const VIBRATO: [i32; 8] = [0, 1, 2, 1, 0, -1, -2, -1];
+
+const ENVELOPE_MAX: u32 = 127;
+const ATTENUATION_STEPS: usize = 512;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum EnvelopeState {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off,
+}
+
+#[derive(Copy, Clone)]
+struct Operator {
+    phase: u32,
+    envelope: u32,
+    state: EnvelopeState,
+    output: i32,
+    previous: i32,
+}
+
+impl Operator {
+    fn new() -> Operator {
+        Operator {
+            phase: 0,
+            envelope: ENVELOPE_MAX,
+            state: EnvelopeState::Off,
+            output: 0,
+            previous: 0,
+        }
+    }
+
+    fn save_state(&self, out: &mut StateWriter) {
+        out.write_u32(self.phase);
+        out.write_u32(self.envelope);
+        out.write_u8(self.state as u8);
+        out.write_i32(self.output);
+        out.write_i32(self.previous);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> io::Result<()> {
+        self.phase = input.read_u32()?;
+        self.envelope = input.read_u32()?.min(ENVELOPE_MAX);
+        self.state = match input.read_u8()? {
+            0 => EnvelopeState::Attack,
+            1 => EnvelopeState::Decay,
+            2 => EnvelopeState::Sustain,
+            3 => EnvelopeState::Release,
+            _ => EnvelopeState::Off,
+        };
+        self.output = input.read_i32()?;
+        self.previous = input.read_i32()?;
+        Ok(())
+    }
+}
+
+struct OperatorPatch {
+    am: bool,
+    vibrato: bool,
+    sustained: bool,
+    key_scale_rate: bool,
+    multiple: usize,
+    key_scale_level: u8,
+    total_level: u8,
+    rectified: bool,
+    feedback: u8,
+    attack: u8,
+    decay: u8,
+    sustain_level: u8,
+    release: u8,
+}
+
+impl OperatorPatch {
+    fn decode(bytes: &[u8; 8], carrier: bool) -> OperatorPatch {
+        let i = carrier as usize;
+        OperatorPatch {
+            am: bytes[i] & 0x80 != 0,
+            vibrato: bytes[i] & 0x40 != 0,
+            sustained: bytes[i] & 0x20 != 0,
+            key_scale_rate: bytes[i] & 0x10 != 0,
+            multiple: (bytes[i] & 0x0F) as usize,
+            key_scale_level: bytes[2 + i] >> 6,
+            total_level: if carrier { 0 } else { bytes[2] & 0x3F },
+            rectified: bytes[3] & (if carrier { 0x10 } else { 0x08 }) != 0,
+            feedback: if carrier { 0 } else { bytes[3] & 0x07 },
+            attack: bytes[4 + i] >> 4,
+            decay: bytes[4 + i] & 0x0F,
+            sustain_level: bytes[6 + i] >> 4,
+            release: bytes[6 + i] & 0x0F,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Ym2413 {
+    registers: [u8; 0x40],
+    address: u8,
+    control: u8,
+    operators: [Operator; CHANNELS * 2],
+    envelope_counter: u32,
+    noise: u32,
+    cycle: u64,
+    outputs: [i32; CHANNELS],
+    sine: Vec<i32>,
+    attenuation: Vec<i32>,
+}
+
+impl Ym2413 {
+    pub fn new() -> Ym2413 {
+        let sine = (0..1024)
+            .map(|i| ((i as f64 + 0.5) * PI / 512.0).sin() * 4095.0)
+            .map(|value| value.round() as i32)
+            .collect();
+        let attenuation = (0..ATTENUATION_STEPS)
+            .map(|step| (4096.0 * 10f64.powf(-0.375 * step as f64 / 20.0)).round() as i32)
+            .collect();
+        Ym2413 {
+            registers: [0; 0x40],
+            address: 0,
+            control: 0,
+            operators: [Operator::new(); CHANNELS * 2],
+            envelope_counter: 0,
+            noise: 1,
+            cycle: 0,
+            outputs: [0; CHANNELS],
+            sine,
+            attenuation,
+        }
+    }
+
+    pub fn register(&self, index: usize) -> u8 {
+        self.registers[index]
+    }
+
+    // The writes that take a freshly reset chip to the current registers.
+    // Key-on bits go after the frequencies and instruments, and the rhythm register last.
+    pub fn register_writes(&self) -> Vec<(u8, u8)> {
+        (0x00..0x08)
+            .chain(0x10..0x19)
+            .chain(0x30..0x39)
+            .chain(0x20..0x29)
+            .chain(0x0E..0x0F)
+            .map(|register| (register, self.registers[register as usize]))
+            .collect()
+    }
+
+    pub fn write_address(&mut self, value: u8) {
+        self.address = value & 0x3F;
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        let register = self.address as usize;
+        let old = self.registers[register];
+        self.registers[register] = value;
+        match register {
+            0x0E => self.write_rhythm(old, value),
+            0x20..=0x28 => {
+                let channel = register - 0x20;
+                if channel < RHYTHM_CHANNEL || !self.rhythm_mode() {
+                    self.key_channel(channel, old & 0x10 != 0, value & 0x10 != 0);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Japanese SMS audio control (port 0xF2): 0 plays the PSG, 1 the FM unit, 3 both and 2 neither.
+    pub fn write_control(&mut self, value: u8) {
+        self.control = value & 0x03;
+    }
+
+    pub fn read_control(&self) -> u8 {
+        self.control
+    }
+
+    pub fn psg_enabled(&self) -> bool {
+        self.control == 0 || self.control == 3
+    }
+
+    pub fn fm_enabled(&self) -> bool {
+        self.control & 0x01 != 0
+    }
+
+    pub fn rhythm_mode(&self) -> bool {
+        self.registers[0x0E] & 0x20 != 0
+    }
+
+    pub fn run_until(&mut self, cycle: u64) {
+        while self.cycle + CYCLES_PER_SAMPLE <= cycle {
+            self.clock_sample();
+            self.cycle += CYCLES_PER_SAMPLE;
+        }
+    }
+
+    pub fn next_sample(&self) -> u64 {
+        self.cycle + CYCLES_PER_SAMPLE
+    }
+
+    pub fn output(&self) -> i32 {
+        self.outputs.iter().sum()
+    }
+
+    // In rhythm mode channel 6 carries the bass drum, 7 the hi-hat and snare, 8 the tom and cymbal.
+    pub fn channel_output(&self, channel: usize) -> i32 {
+        self.outputs[channel]
+    }
+
+    fn write_rhythm(&mut self, old: u8, value: u8) {
+        let was = if old & 0x20 != 0 { old } else { 0 };
+        let now = if value & 0x20 != 0 { value } else { 0 };
+        let edge = |bit: u8| (was & bit != 0, now & bit != 0);
+        let (before, after) = edge(0x10);
+        self.key_operator(RHYTHM_CHANNEL * 2, before, after);
+        self.key_operator(RHYTHM_CHANNEL * 2 + 1, before, after);
+        // HH, SD, TOM and TC each own a single operator of channels 7 and 8.
+        for &(bit, operator) in &[(0x01, 14), (0x08, 15), (0x04, 16), (0x02, 17)] {
+            let (before, after) = edge(bit);
+            self.key_operator(operator, before, after);
+        }
+    }
+
+    fn key_channel(&mut self, channel: usize, before: bool, after: bool) {
+        self.key_operator(channel * 2, before, after);
+        self.key_operator(channel * 2 + 1, before, after);
+    }
+
+    fn key_operator(&mut self, index: usize, before: bool, after: bool) {
+        let operator = &mut self.operators[index];
+        if after && !before {
+            operator.state = EnvelopeState::Attack;
+            operator.phase = 0;
+        } else if before && !after && operator.state != EnvelopeState::Off {
+            operator.state = EnvelopeState::Release;
+        }
+    }
+
+    fn instrument(&self, channel: usize) -> [u8; 8] {
+        if self.rhythm_mode() && channel >= RHYTHM_CHANNEL {
+            return INSTRUMENTS[16 + channel - RHYTHM_CHANNEL];
+        }
+        match self.registers[0x30 + channel] >> 4 {
+            0 => {
+                let mut patch = [0; 8];
+                patch.copy_from_slice(&self.registers[0..8]);
+                patch
+            }
+            index => INSTRUMENTS[index as usize],
+        }
+    }
+
+    fn f_number(&self, channel: usize) -> u32 {
+        self.registers[0x10 + channel] as u32
+            | ((self.registers[0x20 + channel] as u32 & 0x01) << 8)
+    }
+
+    fn block(&self, channel: usize) -> u32 {
+        (self.registers[0x20 + channel] as u32 >> 1) & 0x07
+    }
+
+    fn clock_sample(&mut self) {
+        self.envelope_counter = self.envelope_counter.wrapping_add(1);
+        if self.noise & 1 != 0 {
+            self.noise ^= 0x0080_0302;
+        }
+        self.noise >>= 1;
+
+        let vibrato = VIBRATO[(self.envelope_counter >> 10) as usize & 7];
+        let am = self.am_level();
+        for channel in 0..CHANNELS {
+            let patch = self.instrument(channel);
+            for carrier in &[false, true] {
+                let operator = OperatorPatch::decode(&patch, *carrier);
+                self.update_operator(channel, channel * 2 + *carrier as usize, &operator, vibrato);
+            }
+        }
+
+        let melodic = if self.rhythm_mode() {
+            RHYTHM_CHANNEL
+        } else {
+            CHANNELS
+        };
+        for channel in 0..melodic {
+            self.outputs[channel] = self.render_channel(channel, am);
+        }
+        if self.rhythm_mode() {
+            let (bass_drum, hi_hat_snare, tom_cymbal) = self.render_rhythm(am);
+            self.outputs[RHYTHM_CHANNEL] = bass_drum * 2;
+            self.outputs[RHYTHM_CHANNEL + 1] = hi_hat_snare * 2;
+            self.outputs[RHYTHM_CHANNEL + 2] = tom_cymbal * 2;
+        }
+    }
+
+    // A triangle of 0-13 envelope units (about 4.8dB) at roughly 3.7Hz.
+    fn am_level(&self) -> u32 {
+        let step = (self.envelope_counter >> 8) % 52;
+        let level = if step < 26 { step } else { 52 - step };
+        level / 2
+    }
+
+    fn update_operator(
+        &mut self,
+        channel: usize,
+        index: usize,
+        patch: &OperatorPatch,
+        vibrato: i32,
+    ) {
+        let f_number = self.f_number(channel);
+        let block = self.block(channel);
+        let sustain_on = self.registers[0x20 + channel] & 0x20 != 0;
+
+        let mut frequency = f_number as i32;
+        if patch.vibrato {
+            frequency += ((f_number >> 6) as i32 * vibrato) >> 1;
+        }
+        let increment = (((frequency.max(0) as u32) << block) * MULTIPLIERS[patch.multiple]) >> 1;
+
+        let key_scale = (block << 1) | (f_number >> 8);
+        let key_scale = if patch.key_scale_rate {
+            key_scale
+        } else {
+            key_scale >> 2
+        };
+        let counter = self.envelope_counter;
+
+        let operator = &mut self.operators[index];
+        operator.phase = (operator.phase + increment) & 0x7FFFF;
+
+        let rate = match operator.state {
+            EnvelopeState::Attack => patch.attack,
+            EnvelopeState::Decay => patch.decay,
+            EnvelopeState::Sustain if patch.sustained => 0,
+            EnvelopeState::Sustain => patch.release,
+            EnvelopeState::Release if sustain_on => 5,
+            EnvelopeState::Release if patch.sustained => patch.release,
+            EnvelopeState::Release => 7,
+            EnvelopeState::Off => 0,
+        };
+        if rate == 0 {
+            return;
+        }
+        let rate = (rate as u32 * 4 + key_scale).min(63);
+        let shift = 13u32.saturating_sub(rate / 4);
+        if counter & ((1 << shift) - 1) != 0 {
+            return;
+        }
+        let step = ENVELOPE_STEPS[rate as usize & 3][(counter >> shift) as usize & 7];
+        let increment = step << (rate / 4).saturating_sub(13);
+
+        match operator.state {
+            EnvelopeState::Attack => {
+                if rate >= 60 {
+                    operator.envelope = 0;
+                } else if increment > 0 {
+                    let delta = ((operator.envelope + 1) * increment) >> 3;
+                    operator.envelope -= delta.max(1).min(operator.envelope);
+                }
+                if operator.envelope == 0 {
+                    operator.state = EnvelopeState::Decay;
+                }
+            }
+            EnvelopeState::Decay => {
+                operator.envelope = (operator.envelope + increment).min(ENVELOPE_MAX);
+                if operator.envelope >= patch.sustain_level as u32 * 8 {
+                    operator.state = EnvelopeState::Sustain;
+                }
+            }
+            EnvelopeState::Sustain | EnvelopeState::Release => {
+                operator.envelope = (operator.envelope + increment).min(ENVELOPE_MAX);
+                if operator.envelope == ENVELOPE_MAX && operator.state == EnvelopeState::Release {
+                    operator.state = EnvelopeState::Off;
+                }
+            }
+            EnvelopeState::Off => {}
+        }
+    }
+
+    fn key_scale_level(&self, channel: usize, patch: &OperatorPatch) -> i32 {
+        if patch.key_scale_level == 0 {
+            return 0;
+        }
+        let level = KEY_SCALE_LEVELS[(self.f_number(channel) >> 5) as usize]
+            - 16 * (7 - self.block(channel) as i32);
+        if level <= 0 {
+            0
+        } else {
+            level >> (3 - patch.key_scale_level)
+        }
+    }
+
+    fn volume(&self, channel: usize) -> i32 {
+        (self.registers[0x30 + channel] & 0x0F) as i32 * 8
+    }
+
+    // Rhythm channels 7 and 8 take their modulator volume from the instrument nibble.
+    fn rhythm_volume(&self, channel: usize) -> i32 {
+        (self.registers[0x30 + channel] >> 4) as i32 * 8
+    }
+
+    fn slot_output(
+        &mut self,
+        index: usize,
+        patch: &OperatorPatch,
+        sine_index: u32,
+        level: i32,
+        am: u32,
+    ) -> i32 {
+        let sine_index = sine_index as usize & 0x3FF;
+        let operator = self.operators[index];
+        let mut total = operator.envelope as i32 + level;
+        if patch.am {
+            total += am as i32;
+        }
+        let output = if operator.state == EnvelopeState::Off
+            || total >= ATTENUATION_STEPS as i32
+            || (patch.rectified && sine_index >= 512)
+        {
+            0
+        } else {
+            (self.sine[sine_index] * self.attenuation[total as usize]) >> 12
+        };
+        let operator = &mut self.operators[index];
+        operator.previous = operator.output;
+        operator.output = output;
+        output
+    }
+
+    fn render_channel(&mut self, channel: usize, am: u32) -> i32 {
+        let patch = self.instrument(channel);
+        let modulator = OperatorPatch::decode(&patch, false);
+        let carrier = OperatorPatch::decode(&patch, true);
+
+        let index = channel * 2;
+        let feedback = if modulator.feedback > 0 {
+            let operator = &self.operators[index];
+            (operator.output + operator.previous) >> (9 - modulator.feedback)
+        } else {
+            0
+        };
+        let level = modulator.total_level as i32 * 2 + self.key_scale_level(channel, &modulator);
+        let phase = (self.operators[index].phase >> 9) as i32 + feedback;
+        let modulation = self.slot_output(index, &modulator, phase as u32, level, am);
+
+        let level = self.volume(channel) + self.key_scale_level(channel, &carrier);
+        let phase = (self.operators[index + 1].phase >> 9) as i32 + (modulation >> 1);
+        self.slot_output(index + 1, &carrier, phase as u32, level, am)
+    }
+
+    fn render_rhythm(&mut self, am: u32) -> (i32, i32, i32) {
+        let bass_drum = self.render_channel(RHYTHM_CHANNEL, am);
+
+        let hi_hat_patch = INSTRUMENTS[17];
+        let tom_patch = INSTRUMENTS[18];
+        let hi_hat = OperatorPatch::decode(&hi_hat_patch, false);
+        let snare = OperatorPatch::decode(&hi_hat_patch, true);
+        let tom = OperatorPatch::decode(&tom_patch, false);
+        let cymbal = OperatorPatch::decode(&tom_patch, true);
+
+        let noise = self.noise & 1 != 0;
+        let phase_7 = self.operators[14].phase >> 9;
+        let phase_8 = self.operators[17].phase >> 9;
+        let bit = |value: u32, index: u32| (value >> index) & 1 != 0;
+        let ring_1 = (bit(phase_7, 2) ^ bit(phase_7, 7)) | bit(phase_7, 3);
+        let ring_2 = bit(phase_8, 3) ^ bit(phase_8, 5);
+        let ring = ring_1 | ring_2;
+
+        let hi_hat_phase = match (ring, noise) {
+            (true, true) => 0x2D0,
+            (true, false) => 0x200 | (0xD0 >> 2),
+            (false, true) => 0x34,
+            (false, false) => 0xD0,
+        };
+        let level = self.rhythm_volume(7) + self.key_scale_level(7, &hi_hat);
+        let hi_hat = self.slot_output(14, &hi_hat, hi_hat_phase, level, am);
+
+        let snare_phase =
+            if bit(phase_7, 8) { 0x200 } else { 0x100 } ^ if noise { 0x100 } else { 0 };
+        let level = self.volume(7) + self.key_scale_level(7, &snare);
+        let snare = self.slot_output(15, &snare, snare_phase, level, am);
+
+        let level = self.rhythm_volume(8) + self.key_scale_level(8, &tom);
+        let tom_phase = self.operators[16].phase >> 9;
+        let tom = self.slot_output(16, &tom, tom_phase, level, am);
+
+        let cymbal_phase = if ring { 0x300 } else { 0x100 };
+        let level = self.volume(8) + self.key_scale_level(8, &cymbal);
+        let cymbal = self.slot_output(17, &cymbal, cymbal_phase, level, am);
+
+        (bass_drum, hi_hat + snare, tom + cymbal)
+    }
+
+    pub(crate) fn save_state(&self, out: &mut StateWriter) {
+        out.write_bytes(&self.registers);
+        out.write_u8(self.address);
+        out.write_u8(self.control);
+        for operator in &self.operators {
+            operator.save_state(out);
+        }
+        out.write_u32(self.envelope_counter);
+        out.write_u32(self.noise);
+        out.write_u64(self.cycle);
+        for &output in &self.outputs {
+            out.write_i32(output);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, input: &mut StateReader) -> io::Result<()> {
+        input.read_bytes(&mut self.registers)?;
+        self.address = input.read_u8()? & 0x3F;
+        self.control = input.read_u8()?;
+        for operator in &mut self.operators {
+            operator.load_state(input)?;
+        }
+        self.envelope_counter = input.read_u32()?;
+        self.noise = input.read_u32()?;
+        self.cycle = input.read_u64()?;
+        for output in &mut self.outputs {
+            *output = input.read_i32()?;
+        }
+        Ok(())
+    }
+}
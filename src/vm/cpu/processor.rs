@@ -1,10 +1,16 @@
+use std::io;
 use vm::cpu::alu;
 use vm::cpu::registers::Registers;
 use vm::cpu::state::State;
+use vm::savestate::{StateReader, StateWriter};
 
+#[derive(Clone)]
 pub struct Processor {
     pub state: State,
     halted: bool,
+    cycles: u64,
+    nmi_pending: bool,
+    interrupt_delay: bool,
 }
 
 impl Processor {
@@ -12,6 +18,9 @@ impl Processor {
         Processor {
             state: State::new(),
             halted: true,
+            cycles: 0,
+            nmi_pending: false,
+            interrupt_delay: false,
         }
     }
 
@@ -25,6 +34,46 @@ impl Processor {
         self.halted = false;
     }
 
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub(crate) fn add_cycles(&mut self, tstates: u8) {
+        self.cycles += tstates as u64;
+    }
+
+    pub(crate) fn idle_until(&mut self, cycle: u64) {
+        self.cycles = self.cycles.max(cycle);
+    }
+
+    // The NMI line is edge-triggered, so pulses that arrive before the CPU responds count once.
+    pub fn request_nmi(&mut self) {
+        self.nmi_pending = true;
+    }
+
+    pub fn is_nmi_pending(&self) -> bool {
+        self.nmi_pending
+    }
+
+    pub(crate) fn acknowledge_nmi(&mut self) -> bool {
+        let pending = self.nmi_pending;
+        self.nmi_pending = false;
+        pending
+    }
+
+    // EI holds off maskable interrupts until the instruction after it has run.
+    pub(crate) fn delay_interrupts(&mut self) {
+        self.interrupt_delay = true;
+    }
+
+    pub(crate) fn end_interrupt_delay(&mut self) {
+        self.interrupt_delay = false;
+    }
+
+    pub fn accepts_interrupts(&self) -> bool {
+        self.state.iff1 && !self.interrupt_delay
+    }
+
     pub fn goto(&mut self, address: u16) {
         self.state.program_counter = address;
     }
@@ -37,4 +86,21 @@ impl Processor {
         let (high, low) = selector(&self.state.registers);
         alu::get_word(high, low)
     }
+
+    pub(crate) fn save_state(&self, out: &mut StateWriter) {
+        self.state.save_state(out);
+        out.write_bool(self.halted);
+        out.write_u64(self.cycles);
+        out.write_bool(self.nmi_pending);
+        out.write_bool(self.interrupt_delay);
+    }
+
+    pub(crate) fn load_state(&mut self, input: &mut StateReader) -> io::Result<()> {
+        self.state.load_state(input)?;
+        self.halted = input.read_bool()?;
+        self.cycles = input.read_u64()?;
+        self.nmi_pending = input.read_bool()?;
+        self.interrupt_delay = input.read_bool()?;
+        Ok(())
+    }
 }
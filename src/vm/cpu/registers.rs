@@ -1,5 +1,8 @@
+use std::io;
 use vm::cpu::alu;
+use vm::savestate::{StateReader, StateWriter};
 
+#[derive(Clone)]
 pub struct Registers {
     pub a: u8,
     pub b: u8,
@@ -61,4 +64,29 @@ impl Registers {
         let (high, low) = target(self);
         alu::get_word(*high, *low)
     }
+
+    pub(crate) fn save_state(&self, out: &mut StateWriter) {
+        out.write_bytes(&[
+            self.a, self.b, self.c, self.d, self.e, self.f, self.h, self.l, self.s, self.p,
+        ]);
+    }
+
+    pub(crate) fn load_state(&mut self, input: &mut StateReader) -> io::Result<()> {
+        let mut values = [0; 10];
+        input.read_bytes(&mut values)?;
+        let [a, b, c, d, e, f, h, l, s, p] = values;
+        *self = Registers {
+            a,
+            b,
+            c,
+            d,
+            e,
+            f,
+            h,
+            l,
+            s,
+            p,
+        };
+        Ok(())
+    }
 }
@@ -1,10 +1,15 @@
+use std::io;
 use vm::cpu::registers::Registers;
+use vm::savestate::{StateReader, StateWriter};
 
+#[derive(Clone)]
 pub struct State {
     pub registers: Registers,
     pub alt_registers: Registers,
     pub program_counter: u16,
     pub status: u8,
+    pub iff1: bool,
+    pub iff2: bool,
 }
 
 impl State {
@@ -14,6 +19,27 @@ impl State {
             alt_registers: Registers::new(),
             program_counter: 0,
             status: 0,
+            iff1: false,
+            iff2: false,
         }
     }
+
+    pub(crate) fn save_state(&self, out: &mut StateWriter) {
+        self.registers.save_state(out);
+        self.alt_registers.save_state(out);
+        out.write_u16(self.program_counter);
+        out.write_u8(self.status);
+        out.write_bool(self.iff1);
+        out.write_bool(self.iff2);
+    }
+
+    pub(crate) fn load_state(&mut self, input: &mut StateReader) -> io::Result<()> {
+        self.registers.load_state(input)?;
+        self.alt_registers.load_state(input)?;
+        self.program_counter = input.read_u16()?;
+        self.status = input.read_u8()?;
+        self.iff1 = input.read_bool()?;
+        self.iff2 = input.read_bool()?;
+        Ok(())
+    }
 }
@@ -81,7 +81,7 @@ impl Machine {
         let op1 = self.cpu.state.registers.a;
         let op2 = operand;
         let result = operation(op1, op2);
-        let parity = (0..8).fold(0, |acc, b| acc + (result >> b) & 1) % 2 == 0;
+        let parity = (0..8).fold(0, |acc, b| acc + ((result >> b) & 1)) % 2 == 0;
 
         let status = &mut self.cpu.state.status;
         Flag::ParityOverflow.set(status, parity);
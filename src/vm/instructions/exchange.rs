@@ -2,6 +2,8 @@ use std::mem;
 use vm::cpu::registers::Registers;
 use vm::machine::Machine;
 
+type PairSelector = fn(&mut Registers) -> (&mut u8, &mut u8);
+
 impl Machine {
     pub(crate) fn shadow_exchange_af(&mut self) {
         self.exchange_with_shadow(vec![|regs| &mut regs.a, |regs| &mut regs.f]);
@@ -39,7 +41,7 @@ impl Machine {
         self.clock(19);
     }
 
-    fn exchange(&mut self, selectors: Vec<fn(&mut Registers) -> (&mut u8, &mut u8)>) {
+    fn exchange(&mut self, selectors: Vec<PairSelector>) {
         let reg = &mut self.cpu.state.registers;
         for s in selectors {
             let (r1, r2) = s(reg);
@@ -0,0 +1,63 @@
+use vm::machine::Machine;
+
+const NMI_HANDLER: u16 = 0x0066;
+const IRQ_HANDLER: u16 = 0x0038;
+
+impl Machine {
+    // Called between instructions; either interrupt also wakes a halted CPU.
+    // The VDP holds its IRQ line until the status port is read.
+    // Returns whether one was taken.
+    pub(crate) fn service_interrupts(&mut self) -> bool {
+        if self.cpu.acknowledge_nmi() {
+            self.cpu.unhalt();
+            self.push_program_counter_to_stack();
+            self.cpu.state.iff2 = self.cpu.state.iff1;
+            self.cpu.state.iff1 = false;
+            self.cpu.goto(NMI_HANDLER);
+            self.clock(11);
+            true
+        } else if self.vdp.irq_pending() && self.cpu.accepts_interrupts() {
+            self.cpu.unhalt();
+            self.push_program_counter_to_stack();
+            self.cpu.state.iff1 = false;
+            self.cpu.state.iff2 = false;
+            self.cpu.goto(IRQ_HANDLER);
+            self.clock(13);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn disable_interrupts(&mut self) {
+        self.cpu.state.iff1 = false;
+        self.cpu.state.iff2 = false;
+        self.clock(4);
+    }
+
+    pub(crate) fn enable_interrupts(&mut self) {
+        self.cpu.state.iff1 = true;
+        self.cpu.state.iff2 = true;
+        self.cpu.delay_interrupts();
+        self.clock(4);
+    }
+
+    // Only RETN, RETI and IM are decoded so far; any other ED opcode stops the emulator rather than
+    // running its operands as instructions.
+    pub(crate) fn execute_extended(&mut self) {
+        let address = self.cpu.state.program_counter.wrapping_sub(1);
+        match self.next_byte() {
+            0x45 | 0x4D => self.return_from_interrupt(),
+            // IM 0 and IM 1: the SMS data bus reads 0xFF, so IM 0 also ends up at 0x0038.
+            // IM 2 would need the I register, which is not emulated.
+            0x46 | 0x56 => self.clock(8),
+            opcode => panic!("unsupported opcode ED {:02X} at {:04X}", opcode, address),
+        }
+    }
+
+    fn return_from_interrupt(&mut self) {
+        self.pop_stack_to_program_counter();
+        self.cpu.state.iff1 = self.cpu.state.iff2;
+        self.clock(14);
+    }
+}
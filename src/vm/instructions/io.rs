@@ -0,0 +1,16 @@
+use vm::machine::Machine;
+
+impl Machine {
+    pub(crate) fn output_accumulator_to_param_port(&mut self) {
+        let port = self.next_byte();
+        let value = self.cpu.state.registers.a;
+        self.write_port(port, value);
+        self.clock(11);
+    }
+
+    pub(crate) fn input_param_port_to_accumulator(&mut self) {
+        let port = self.next_byte();
+        self.cpu.state.registers.a = self.read_port(port);
+        self.clock(11);
+    }
+}
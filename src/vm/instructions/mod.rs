@@ -3,6 +3,8 @@ mod arithmetic_8bit;
 mod bitwise;
 mod control;
 mod exchange;
+mod interrupts;
+mod io;
 mod memory;
 pub mod opcodes;
 mod stack;
@@ -13,6 +15,7 @@ use vm::machine::Machine;
 
 impl Machine {
     pub fn execute(&mut self) {
+        self.cpu.end_interrupt_delay();
         let opcode = Opcode::from(self.next_byte());
         match opcode {
             Opcode::Nop => self.nop(),
@@ -267,10 +270,17 @@ impl Machine {
             Opcode::PopDE => self.pop_from_stack(|regs| (&mut regs.d, &mut regs.e)),
             Opcode::PopHL => self.pop_from_stack(|regs| (&mut regs.h, &mut regs.l)),
 
-            Opcode::SCF => self.set_carry_flag(),
-            Opcode::CCF => self.complement_carry_flag(),
-            Opcode::CPL => self.complement_registers(|regs| &mut regs.a),
-            Opcode::RLCA => self.rotate_accumulator_left(),
+            Opcode::Scf => self.set_carry_flag(),
+            Opcode::Ccf => self.complement_carry_flag(),
+            Opcode::Cpl => self.complement_registers(|regs| &mut regs.a),
+            Opcode::Rlca => self.rotate_accumulator_left(),
+
+            Opcode::OutVXA => self.output_accumulator_to_param_port(),
+            Opcode::InAVX => self.input_param_port_to_accumulator(),
+
+            Opcode::Di => self.disable_interrupts(),
+            Opcode::Ei => self.enable_interrupts(),
+            Opcode::ExtendedPrefix => self.execute_extended(),
 
             Opcode::Halt => self.halt(),
         }
@@ -300,7 +310,7 @@ impl Machine {
         (high << 8) | low
     }
 
-    pub fn clock(&mut self, _tstates: u8) {
-        // TODO: Something.
+    pub fn clock(&mut self, tstates: u8) {
+        self.cpu.add_cycles(tstates);
     }
 }
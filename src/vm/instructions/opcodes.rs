@@ -8,7 +8,7 @@ pub enum Opcode {
     IncB = 0x04,
     DecB = 0x05,
     LdBX = 0x06,
-    RLCA = 0x07,
+    Rlca = 0x07,
     ExAFAF = 0x08,
     AddHLBC = 0x09,
     LdAVBC = 0x0A,
@@ -42,20 +42,20 @@ pub enum Opcode {
     IncL = 0x2C,
     DecL = 0x2D,
     LdLX = 0x2E,
-    CPL = 0x2F,
+    Cpl = 0x2F,
 
     LdSPXX = 0x31,
     LdVXXA = 0x32,
     IncSP = 0x33,
     LdVHLX = 0x36,
-    SCF = 0x37,
+    Scf = 0x37,
     AddHLSP = 0x39,
     LdAVXX = 0x3A,
     DecSP = 0x3B,
     IncA = 0x3C,
     DecA = 0x3D,
     LdAX = 0x3E,
-    CCF = 0x3F,
+    Ccf = 0x3F,
 
     LdBB = 0x40,
     LdBC = 0x41,
@@ -193,11 +193,13 @@ pub enum Opcode {
     RetNC = 0xD0,
     PopDE = 0xD1,
     JpNCXX = 0xD2,
+    OutVXA = 0xD3,
     CallNCXX = 0xD4,
     PushDE = 0xD5,
     RetC = 0xD8,
     Exx = 0xD9,
     JpCXX = 0xDA,
+    InAVX = 0xDB,
     CallCXX = 0xDC,
 
     RetPO = 0xE0,
@@ -211,16 +213,19 @@ pub enum Opcode {
     JpPEXX = 0xEA,
     ExDEHL = 0xEB,
     CallPEXX = 0xEC,
+    ExtendedPrefix = 0xED,
     XorX = 0xEE,
 
     RetP = 0xF0,
     PopAF = 0xF1,
     JpPXX = 0xF2,
+    Di = 0xF3,
     CallPXX = 0xF4,
     PushAF = 0xF5,
     OrX = 0xF6,
     RetM = 0xF8,
     JpMXX = 0xFA,
+    Ei = 0xFB,
     CallMXX = 0xFC,
 }
 
@@ -25,8 +25,8 @@ impl Machine {
 
     pub(crate) fn pop_from_stack(&mut self, selector: fn(&mut Registers) -> (&mut u8, &mut u8)) {
         let sp = Registers::u8s_to_u16(self.cpu.state.registers.s, self.cpu.state.registers.p);
-        let high_val = self.ram.read_u8(sp);
-        let low_val = self.ram.read_u8(sp + 1);
+        let low_val = self.ram.read_u8(sp);
+        let high_val = self.ram.read_u8(sp + 1);
         {
             let (high_reg, low_reg) = selector(&mut self.cpu.state.registers);
             *high_reg = high_val;
@@ -40,8 +40,8 @@ impl Machine {
 
     pub(crate) fn pop_stack_to_program_counter(&mut self) {
         let sp = Registers::u8s_to_u16(self.cpu.state.registers.s, self.cpu.state.registers.p);
-        let high_val = self.ram.read_u8(sp);
-        let low_val = self.ram.read_u8(sp + 1);
+        let low_val = self.ram.read_u8(sp);
+        let high_val = self.ram.read_u8(sp + 1);
         self.cpu.state.program_counter = Registers::u8s_to_u16(high_val, low_val);
         let (s, p) = Registers::u16_to_u8s(sp + 2);
         self.cpu.state.registers.s = s;
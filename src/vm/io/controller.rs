@@ -0,0 +1,417 @@
+use std::io;
+use vm::savestate;
+use vm::savestate::{StateReader, StateWriter};
+use vm::video::vdp;
+
+// One standard control pad; true means held down.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Buttons {
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
+    pub button1: bool,
+    pub button2: bool,
+}
+
+impl Buttons {
+    // Up, Down, Left, Right, button 1 and button 2 in bits 0-5, set while held.
+    pub fn bits(&self) -> u8 {
+        let pressed = [
+            self.up,
+            self.down,
+            self.left,
+            self.right,
+            self.button1,
+            self.button2,
+        ];
+        pressed.iter().enumerate().fold(
+            0,
+            |bits, (bit, &down)| if down { bits | (1 << bit) } else { bits },
+        )
+    }
+
+    pub fn from_bits(bits: u8) -> Buttons {
+        let held = |bit: u8| bits & (1 << bit) != 0;
+        Buttons {
+            up: held(0),
+            down: held(1),
+            left: held(2),
+            right: held(3),
+            button1: held(4),
+            button2: held(5),
+        }
+    }
+
+    // The same bits as the port sees them: TL and TR are buttons 1 and 2, active low.
+    fn lines(&self) -> u8 {
+        !self.bits() & 0x3F
+    }
+}
+
+// Aim in 256-pixel-wide screen coordinates; TL is the trigger.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Phaser {
+    pub x: u16,
+    pub y: u16,
+    pub trigger: bool,
+}
+
+// Lines after the aim row on which the phaser sees the beam.
+pub const PHASER_RADIUS: u16 = 4;
+// Pixels after the aim point during which TH stays low.
+const PHASER_SPOT_WIDTH: u64 = 16;
+
+// The Japanese HPD-200 paddle; the button is on TL.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Paddle {
+    pub position: u8,
+    pub button: bool,
+}
+
+// Trackball position, wrapping; the pad reports how far it moved since the previous read-out.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct SportsPad {
+    pub x: u8,
+    pub y: u8,
+    pub button1: bool,
+    pub button2: bool,
+}
+
+// Japanese peripherals do not expect to be clocked through TH, so they switch nibbles on a timer.
+const PADDLE_NIBBLE_CYCLES: u64 = 448;
+const SPORTS_PAD_NIBBLE_CYCLES: u64 = 448;
+
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub enum Peripheral {
+    #[default]
+    Joypad,
+    LightPhaser(Phaser),
+    Paddle(Paddle),
+    SportsPad(SportsPad),
+}
+
+impl Peripheral {
+    // A type byte followed by the peripheral's fields; shared by save states and movies.
+    pub(crate) fn save_state(&self, out: &mut StateWriter) {
+        match *self {
+            Peripheral::Joypad => out.write_u8(0),
+            Peripheral::LightPhaser(phaser) => {
+                out.write_u8(1);
+                out.write_u16(phaser.x);
+                out.write_u16(phaser.y);
+                out.write_bool(phaser.trigger);
+            }
+            Peripheral::Paddle(paddle) => {
+                out.write_u8(2);
+                out.write_u8(paddle.position);
+                out.write_bool(paddle.button);
+            }
+            Peripheral::SportsPad(pad) => {
+                out.write_u8(3);
+                out.write_bytes(&[pad.x, pad.y]);
+                out.write_bool(pad.button1);
+                out.write_bool(pad.button2);
+            }
+        }
+    }
+
+    pub(crate) fn load_state(input: &mut StateReader) -> io::Result<Peripheral> {
+        Ok(match input.read_u8()? {
+            0 => Peripheral::Joypad,
+            1 => Peripheral::LightPhaser(Phaser {
+                x: input.read_u16()?,
+                y: input.read_u16()?,
+                trigger: input.read_bool()?,
+            }),
+            2 => Peripheral::Paddle(Paddle {
+                position: input.read_u8()?,
+                button: input.read_bool()?,
+            }),
+            3 => Peripheral::SportsPad(SportsPad {
+                x: input.read_u8()?,
+                y: input.read_u8()?,
+                button1: input.read_bool()?,
+                button2: input.read_bool()?,
+            }),
+            _ => return Err(savestate::invalid("unknown peripheral")),
+        })
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+struct SportsPadState {
+    step: u8,
+    last: (u8, u8),
+    motion: (u8, u8),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Pin {
+    Tr,
+    Th,
+}
+
+// The two controller ports, the Reset button and the I/O control register (port 0x3F).
+#[derive(Clone)]
+pub struct Controllers {
+    pads: [Buttons; 2],
+    peripherals: [Peripheral; 2],
+    sports_pads: [SportsPadState; 2],
+    lit: [bool; 2],
+    reset: bool,
+    io_control: u8,
+    japanese: bool,
+}
+
+impl Controllers {
+    pub fn new() -> Controllers {
+        Controllers {
+            pads: [Buttons::default(); 2],
+            peripherals: [Peripheral::Joypad; 2],
+            sports_pads: [SportsPadState::default(); 2],
+            lit: [false; 2],
+            reset: false,
+            io_control: 0xFF,
+            japanese: false,
+        }
+    }
+
+    pub fn set_buttons(&mut self, port: usize, buttons: Buttons) {
+        self.pads[port] = buttons;
+    }
+
+    pub fn buttons(&self, port: usize) -> Buttons {
+        self.pads[port]
+    }
+
+    // Also used every frame to move the aim, position or trigger of an attached peripheral.
+    pub fn connect(&mut self, port: usize, peripheral: Peripheral) {
+        self.peripherals[port] = peripheral;
+    }
+
+    pub fn peripheral(&self, port: usize) -> Peripheral {
+        self.peripherals[port]
+    }
+
+    pub fn set_japanese(&mut self, japanese: bool) {
+        self.japanese = japanese;
+    }
+
+    pub fn is_japanese(&self) -> bool {
+        self.japanese
+    }
+
+    // Returns true when the sensor has just started seeing light, which pulls TH low.
+    pub(crate) fn set_light(&mut self, port: usize, lit: bool) -> bool {
+        let falling_edge = lit && !self.lit[port];
+        self.lit[port] = lit;
+        falling_edge
+    }
+
+    pub fn th_is_input(&self, port: usize) -> bool {
+        self.output_level(port, Pin::Th).is_none()
+    }
+
+    pub fn set_reset(&mut self, pressed: bool) {
+        self.reset = pressed;
+    }
+
+    pub fn is_reset_pressed(&self) -> bool {
+        self.reset
+    }
+
+    // Bits 0-3 make P1 TR, P1 TH, P2 TR and P2 TH inputs (1) or outputs (0); bits 4-7 are the output levels.
+    // Export Sports Pads step to their next nibble each time the console toggles TH.
+    pub fn write_io_control(&mut self, value: u8) {
+        let before = [self.th_level(0), self.th_level(1)];
+        self.io_control = value;
+        for (port, &level) in before.iter().enumerate() {
+            if !self.japanese && level != self.th_level(port) {
+                let step = (self.sports_pads[port].step + 1) & 3;
+                self.step_sports_pad(port, step);
+            }
+        }
+    }
+
+    pub fn io_control(&self) -> u8 {
+        self.io_control
+    }
+
+    // Port 0xDC: P1 Up, Down, Left, Right, TL, TR, then P2 Up and Down.
+    pub fn read_port_a(&mut self, cycle: u64) -> u8 {
+        let one = self.port_lines(0, cycle);
+        let two = self.port_lines(1, cycle);
+        (one & 0x3F) | ((two & 0x03) << 6)
+    }
+
+    // Port 0xDD: P2 Left, Right, TL, TR, Reset, cartridge CONT, then P1 TH and P2 TH.
+    pub fn read_port_b(&mut self, cycle: u64) -> u8 {
+        let two = self.port_lines(1, cycle);
+        let mut value = (two >> 2) & 0x0F;
+        if !self.reset {
+            value |= 0x10;
+        }
+        value |= 0x20;
+        let beam = vdp::beam_pixel(cycle);
+        if self.th_input(0, beam) {
+            value |= 0x40;
+        }
+        if self.th_input(1, beam) {
+            value |= 0x80;
+        }
+        value
+    }
+
+    // Bits 0-5 as seen on the port, with an output TR reading back the level the console drives.
+    fn port_lines(&mut self, port: usize, cycle: u64) -> u8 {
+        let lines = self.device_lines(port, cycle);
+        match self.output_level(port, Pin::Tr) {
+            Some(true) => lines | 0x20,
+            Some(false) => lines & !0x20,
+            None => lines,
+        }
+    }
+
+    // Bits 0-5 driven by the peripheral, active low.
+    fn device_lines(&mut self, port: usize, cycle: u64) -> u8 {
+        match self.peripherals[port] {
+            Peripheral::Joypad => self.pads[port].lines(),
+            Peripheral::LightPhaser(phaser) if phaser.trigger => 0x2F,
+            Peripheral::LightPhaser(_) => 0x3F,
+            Peripheral::Paddle(paddle) => {
+                // TR tells the game which nibble it is looking at.
+                let high = if self.japanese {
+                    (cycle / PADDLE_NIBBLE_CYCLES) & 1 != 0
+                } else {
+                    !self.th_level(port)
+                };
+                let nibble = if high {
+                    paddle.position >> 4
+                } else {
+                    paddle.position & 0x0F
+                };
+                nibble | button_line(paddle.button, 0x10) | if high { 0x20 } else { 0 }
+            }
+            Peripheral::SportsPad(pad) => {
+                if self.japanese {
+                    let step = ((cycle / SPORTS_PAD_NIBBLE_CYCLES) & 3) as u8;
+                    self.step_sports_pad(port, step);
+                }
+                let state = self.sports_pads[port];
+                let (x, y) = state.motion;
+                let nibble = match state.step {
+                    1 => x >> 4,
+                    2 => x & 0x0F,
+                    3 => y >> 4,
+                    _ => y & 0x0F,
+                };
+                // The Japanese pad gives up button 2 to flag odd nibbles on TR.
+                let tr = if self.japanese {
+                    if state.step & 1 != 0 {
+                        0x20
+                    } else {
+                        0
+                    }
+                } else {
+                    button_line(pad.button2, 0x20)
+                };
+                nibble | button_line(pad.button1, 0x10) | tr
+            }
+        }
+    }
+
+    // Step 1 starts a new read-out, so the motion since the last one is captured there.
+    fn step_sports_pad(&mut self, port: usize, step: u8) {
+        let pad = match self.peripherals[port] {
+            Peripheral::SportsPad(pad) => pad,
+            _ => return,
+        };
+        let state = &mut self.sports_pads[port];
+        if state.step == step {
+            return;
+        }
+        state.step = step;
+        if step == 1 {
+            state.motion = (
+                pad.x.wrapping_sub(state.last.0),
+                pad.y.wrapping_sub(state.last.1),
+            );
+            state.last = (pad.x, pad.y);
+        }
+    }
+
+    // Japanese consoles read an output TH back inverted, which is how games tell them apart.
+    fn th_input(&self, port: usize, beam: u64) -> bool {
+        if let Some(level) = self.output_level(port, Pin::Th) {
+            return level != self.japanese;
+        }
+        match self.peripherals[port] {
+            Peripheral::LightPhaser(phaser) => {
+                let x = phaser.x as u64;
+                !(self.lit[port] && beam >= x && beam < x + PHASER_SPOT_WIDTH)
+            }
+            _ => true,
+        }
+    }
+
+    // TH as the console drives it; an input floats high.
+    fn th_level(&self, port: usize) -> bool {
+        self.output_level(port, Pin::Th).unwrap_or(true)
+    }
+
+    fn output_level(&self, port: usize, pin: Pin) -> Option<bool> {
+        let bit = port * 2 + if pin == Pin::Th { 1 } else { 0 };
+        if self.io_control & (1 << bit) == 0 {
+            Some(self.io_control & (0x10 << bit) != 0)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn save_state(&self, out: &mut StateWriter) {
+        for port in 0..2 {
+            out.write_u8(self.pads[port].bits());
+            self.peripherals[port].save_state(out);
+            let state = self.sports_pads[port];
+            out.write_bytes(&[
+                state.step,
+                state.last.0,
+                state.last.1,
+                state.motion.0,
+                state.motion.1,
+            ]);
+            out.write_bool(self.lit[port]);
+        }
+        out.write_bool(self.reset);
+        out.write_u8(self.io_control);
+        out.write_bool(self.japanese);
+    }
+
+    pub(crate) fn load_state(&mut self, input: &mut StateReader) -> io::Result<()> {
+        for port in 0..2 {
+            self.pads[port] = Buttons::from_bits(input.read_u8()?);
+            self.peripherals[port] = Peripheral::load_state(input)?;
+            let mut state = [0; 5];
+            input.read_bytes(&mut state)?;
+            self.sports_pads[port] = SportsPadState {
+                step: state[0] & 3,
+                last: (state[1], state[2]),
+                motion: (state[3], state[4]),
+            };
+            self.lit[port] = input.read_bool()?;
+        }
+        self.reset = input.read_bool()?;
+        self.io_control = input.read_u8()?;
+        self.japanese = input.read_bool()?;
+        Ok(())
+    }
+}
+
+fn button_line(pressed: bool, mask: u8) -> u8 {
+    if pressed {
+        0
+    } else {
+        mask
+    }
+}
@@ -0,0 +1,79 @@
+use vm::audio::vgm::VgmCommand;
+use vm::machine::Machine;
+use vm::region::VideoStandard;
+use vm::video::model::Model;
+
+impl Machine {
+    pub fn read_port(&mut self, port: u8) -> u8 {
+        if port == 0x00 && self.vdp.model() == Model::GameGear {
+            return self.game_gear_system_port();
+        }
+        if let Some(ref fm) = self.fm {
+            if port == 0xF2 {
+                return fm.read_control();
+            }
+        }
+        match port & 0xC1 {
+            0x40 => self.vdp.read_v_counter(),
+            0x41 => self.vdp.read_h_counter(),
+            0xC0 => self.controllers.read_port_a(self.cpu.cycles()),
+            0xC1 => self.controllers.read_port_b(self.cpu.cycles()),
+            0x80 => self.vdp.read_data(),
+            0x81 => self.vdp.read_status(),
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_port(&mut self, port: u8, value: u8) {
+        if port == 0x06 && self.vdp.model() == Model::GameGear {
+            let cycle = self.cpu.cycles();
+            self.run_audio_until(cycle);
+            self.psg.write_panning(value);
+            self.log_vgm(VgmCommand::GameGearStereo(value));
+            return;
+        }
+        if self.fm.is_some() && (0xF0..=0xF2).contains(&port) {
+            let cycle = self.cpu.cycles();
+            self.run_audio_until(cycle);
+            let mut logged = None;
+            if let Some(ref mut fm) = self.fm {
+                match port {
+                    0xF0 => fm.write_address(value),
+                    0xF1 => {
+                        logged = Some(VgmCommand::Fm(fm.address(), value));
+                        fm.write_data(value);
+                    }
+                    _ => fm.write_control(value),
+                }
+            }
+            if let Some(command) = logged {
+                self.log_vgm(command);
+            }
+            return;
+        }
+        match port & 0xC1 {
+            0x40 | 0x41 => {
+                let cycle = self.cpu.cycles();
+                self.run_audio_until(cycle);
+                self.psg.write(value);
+                self.log_vgm(VgmCommand::Psg(value));
+            }
+            0x01 => self.controllers.write_io_control(value),
+            0x80 => self.vdp.write_data(value),
+            0x81 => self.vdp.write_control(value),
+            _ => {}
+        }
+    }
+
+    // Port 0x00: Start released, then export (bit 6) and PAL (bit 5).
+    fn game_gear_system_port(&self) -> u8 {
+        let mut value = 0x80;
+        if !self.region().is_japanese() {
+            value |= 0x40;
+        }
+        if self.vdp.video_standard() == VideoStandard::Pal {
+            value |= 0x20;
+        }
+        value
+    }
+}
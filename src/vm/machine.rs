@@ -1,17 +1,116 @@
 use program::Program;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use vm::audio::mixer;
+use vm::audio::mixer::{Channel, Mixer};
+use vm::audio::psg::Psg;
+use vm::audio::ring;
+use vm::audio::ring::{Consumer, Producer};
+use vm::audio::vgm::{VgmCommand, VgmPlayer, VgmWriter};
+use vm::audio::wav::WavWriter;
+use vm::audio::ym2413::Ym2413;
 use vm::cpu::processor::Processor;
+use vm::io::controller;
+use vm::io::controller::{Buttons, Controllers, Peripheral};
+use vm::movie::{FrameInput, Movie};
 use vm::ram::memory::Memory;
+use vm::region::{Region, VideoStandard};
+use vm::rewind::RewindBuffer;
+use vm::savestate;
+use vm::savestate::{StateReader, StateWriter};
+use vm::script::{Action, InputScript};
+use vm::snapshot::Snapshot;
+use vm::video::frame::Frame;
+use vm::video::image;
+use vm::video::model::Model;
+use vm::video::scanline::ScanlineEvent;
+use vm::video::vdp;
+use vm::video::vdp::Vdp;
+
+// About a third of a second of stereo output at the default rate.
+pub const AUDIO_BUFFER_SIZE: usize = 32_768;
+
+// What one call to run_frame produced: the picture and the interleaved samples that go with it.
+pub struct FrameOutput {
+    pub frame: Frame,
+    pub audio: Vec<i16>,
+}
+
+// What one call to Machine::step did.
+pub(crate) enum Step {
+    Interrupt,
+    Instruction,
+    Line,
+    Frame(Vec<i16>),
+}
 
 pub struct Machine {
     pub cpu: Processor,
     pub ram: Memory,
+    pub vdp: Vdp,
+    pub psg: Psg,
+    pub fm: Option<Ym2413>,
+    pub controllers: Controllers,
+    pub mixer: Mixer,
+    region: Region,
+    audio: Producer,
+    audio_reader: Option<Consumer>,
+    recorder: Option<WavWriter<BufWriter<File>>>,
+    channel_recorders: Vec<WavWriter<BufWriter<File>>>,
+    vgm: Option<VgmWriter<BufWriter<File>>>,
+    recording_error: Option<io::Error>,
+    pause_pressed: bool,
+    movie: Option<(Movie, u64)>,
+    playback: Option<(Movie, usize)>,
+    rom_crc: u32,
+    script: Option<(InputScript, u32)>,
+    line_end: Option<u64>,
+    frame: u64,
+    rewind: Option<RewindBuffer>,
+    replaying: bool,
+    silent: bool,
+    run_ahead: u32,
+    running_ahead: bool,
 }
 
 impl Machine {
     pub fn new() -> Machine {
+        Machine::with_model(Model::MasterSystem2)
+    }
+
+    pub fn with_model(model: Model) -> Machine {
+        let (audio, audio_reader) = ring::ring_buffer(AUDIO_BUFFER_SIZE);
         Machine {
             cpu: Processor::new(),
             ram: Memory::new(),
+            vdp: Vdp::new(model),
+            psg: Psg::new(),
+            fm: None,
+            controllers: Controllers::new(),
+            mixer: Mixer::new(mixer::DEFAULT_SAMPLE_RATE, model == Model::GameGear),
+            region: Region::Usa,
+            audio,
+            audio_reader: Some(audio_reader),
+            recorder: None,
+            channel_recorders: Vec::new(),
+            vgm: None,
+            recording_error: None,
+            pause_pressed: false,
+            movie: None,
+            playback: None,
+            rom_crc: 0,
+            script: None,
+            line_end: None,
+            frame: 0,
+            rewind: None,
+            replaying: false,
+            silent: false,
+            run_ahead: 0,
+            running_ahead: false,
         }
     }
 
@@ -28,7 +127,9 @@ impl Machine {
         will_fit
     }
 
+    // Loads a cartridge image at 0 and remembers its CRC-32 so movies can tell it apart.
     pub fn load(&mut self, program: &Program) -> bool {
+        self.rom_crc = image::crc32(program.raw().iter());
         self.load_at(program, 0)
     }
 
@@ -44,4 +145,684 @@ impl Machine {
     pub fn start(&mut self) {
         self.start_at(0);
     }
+
+    // Runs until the current frame is complete; after run_until stopped mid-frame that is only the rest of it.
+    // With run-ahead the picture is the one the game will show that many frames later if the input stays the same.
+    pub fn run_frame(&mut self) -> FrameOutput {
+        let audio = self.finish_frame();
+        if self.run_ahead == 0 {
+            return FrameOutput {
+                frame: self.vdp.frame(),
+                audio,
+            };
+        }
+        let snapshot = self.snapshot();
+        let hook = self.vdp.take_scanline_hook();
+        self.silent = true;
+        self.running_ahead = true;
+        for _ in 0..self.run_ahead {
+            self.finish_frame();
+        }
+        let frame = self.vdp.frame();
+        self.silent = false;
+        self.running_ahead = false;
+        self.restore(&snapshot);
+        self.vdp.restore_scanline_hook(hook);
+        FrameOutput { frame, audio }
+    }
+
+    fn finish_frame(&mut self) -> Vec<i16> {
+        loop {
+            if let Step::Frame(audio) = self.step() {
+                return audio;
+            }
+        }
+    }
+
+    // Hides input lag: each run_frame also emulates `frames` more frames and shows the last of them,
+    // then rolls back so that only the first one counts. Sound, recordings and hooks follow the real frames.
+    pub fn set_run_ahead(&mut self, frames: u32) {
+        self.run_ahead = frames;
+    }
+
+    pub fn run_ahead(&self) -> u32 {
+        self.run_ahead
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            cpu: self.cpu.clone(),
+            ram: self.ram.clone(),
+            vdp: self.vdp.clone(),
+            psg: self.psg.clone(),
+            fm: self.fm.clone(),
+            controllers: self.controllers.clone(),
+            pause_pressed: self.pause_pressed,
+            line_end: self.line_end,
+            frame: self.frame,
+        }
+    }
+
+    // Like load_state, audio resumes from the restored cycle and pending output is dropped,
+    // and rewinding starts over from the snapshot.
+    pub fn restore_snapshot(&mut self, snapshot: &Snapshot) {
+        self.restore(snapshot);
+        self.restart_audio();
+        self.restart_rewind();
+        self.truncate_movie();
+    }
+
+    // Run-ahead restores to the point the mixer is still at, so the sound carries on uninterrupted.
+    fn restore(&mut self, snapshot: &Snapshot) {
+        let hook = self.vdp.take_scanline_hook();
+        self.cpu = snapshot.cpu.clone();
+        self.ram = snapshot.ram.clone();
+        self.vdp = snapshot.vdp.clone();
+        self.vdp.restore_scanline_hook(hook);
+        self.psg = snapshot.psg.clone();
+        self.fm = snapshot.fm.clone();
+        self.controllers = snapshot.controllers.clone();
+        self.pause_pressed = snapshot.pause_pressed;
+        self.line_end = snapshot.line_end;
+        self.frame = snapshot.frame;
+    }
+
+    // Runs the CPU a line at a time, then draws that line and catches the sound chips up to it.
+    // A halted CPU idles while the VDP keeps drawing until an interrupt wakes it.
+    // Each call either runs one instruction or finishes the line, which may finish the frame.
+    pub(crate) fn step(&mut self) -> Step {
+        let line_end = match self.line_end {
+            Some(line_end) => line_end,
+            None => {
+                if self.vdp.line() == 0 && !self.running_ahead {
+                    self.update_input();
+                }
+                self.sense_light_phasers();
+                let line_end =
+                    (self.cpu.cycles() / vdp::CYCLES_PER_LINE + 1) * vdp::CYCLES_PER_LINE;
+                self.line_end = Some(line_end);
+                line_end
+            }
+        };
+        if self.service_interrupts() {
+            return Step::Interrupt;
+        }
+        if !self.cpu.is_halted() && self.cpu.cycles() < line_end {
+            self.execute();
+            return Step::Instruction;
+        }
+        self.line_end = None;
+        self.cpu.idle_until(line_end);
+        self.run_audio_until(line_end);
+        if self.vdp.step_line() {
+            self.frame += 1;
+            if !self.running_ahead
+                && self
+                    .rewind
+                    .as_ref()
+                    .is_some_and(|r| r.wants_snapshot(self.frame))
+            {
+                self.take_snapshot();
+            }
+            Step::Frame(self.capture_audio(line_end))
+        } else {
+            Step::Line
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    // Also switches to the region's usual video standard; call set_video_standard afterwards to override it.
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.controllers.set_japanese(region.is_japanese());
+        self.set_video_standard(region.video_standard());
+    }
+
+    pub(crate) fn save_scheduler_state(&self, out: &mut StateWriter) {
+        out.write_bool(self.pause_pressed);
+        out.write_bool(self.line_end.is_some());
+        out.write_u64(self.line_end.unwrap_or(0));
+        out.write_u64(self.frame);
+    }
+
+    pub(crate) fn load_scheduler_state(
+        input: &mut StateReader,
+    ) -> io::Result<(bool, Option<u64>, u64)> {
+        let pause_pressed = input.read_bool()?;
+        let mid_line = input.read_bool()?;
+        let line_end = input.read_u64()?;
+        let frame = input.read_u64()?;
+        Ok((
+            pause_pressed,
+            if mid_line { Some(line_end) } else { None },
+            frame,
+        ))
+    }
+
+    // Called once the components have been replaced; the sound chips carry on from the loaded cycle.
+    pub(crate) fn restore_scheduler_state(
+        &mut self,
+        region: Region,
+        pause_pressed: bool,
+        line_end: Option<u64>,
+        frame: u64,
+    ) {
+        self.region = region;
+        self.pause_pressed = pause_pressed;
+        self.line_end = line_end;
+        self.frame = frame;
+        self.restart_audio();
+    }
+
+    fn restart_audio(&mut self) {
+        let cycle = self.cpu.cycles();
+        self.mixer.restart(cycle);
+        let clock = self.vdp.video_standard().cpu_clock();
+        if self.mixer.clock() != clock {
+            self.mixer.set_clock(clock, cycle);
+        }
+        if let Some(ref mut vgm) = self.vgm {
+            vgm.restart(cycle);
+        }
+    }
+
+    pub fn video_standard(&self) -> VideoStandard {
+        self.vdp.video_standard()
+    }
+
+    // PAL consoles draw 313 lines a frame from a slower CPU clock.
+    pub fn set_video_standard(&mut self, standard: VideoStandard) {
+        let cycle = self.cpu.cycles();
+        self.run_audio_until(cycle);
+        self.mixer.set_clock(standard.cpu_clock(), cycle);
+        self.vdp.set_video_standard(standard);
+    }
+
+    // `port` is 0 for controller port 1 and 1 for controller port 2.
+    pub fn set_buttons(&mut self, port: usize, state: Buttons) {
+        self.controllers.set_buttons(port, state);
+    }
+
+    // Pause is wired to the Z80 NMI; the game sees it at 0x0066 before the next instruction.
+    pub fn press_pause(&mut self) {
+        self.pause_pressed = true;
+        self.cpu.request_nmi();
+    }
+
+    // Records the input of every following frame; start right after power-on so playback matches.
+    pub fn start_movie_recording(&mut self) {
+        let fm = self.fm.is_some();
+        let movie = Movie::new(
+            self.vdp.model(),
+            self.region,
+            self.video_standard(),
+            fm,
+            self.rom_crc,
+        );
+        self.movie = Some((movie, self.frame));
+    }
+
+    pub fn stop_movie_recording(&mut self) -> Option<Movie> {
+        self.movie.take().map(|(movie, _)| movie)
+    }
+
+    // After a rewind or a loaded state the movie keeps only the input that led to where the machine is now,
+    // including the current frame's when stopped mid-frame.
+    // A frame outside the recording cannot be reached from it, so the recording starts over there.
+    pub(crate) fn truncate_movie(&mut self) {
+        let mid_frame = self.line_end.is_some() || self.vdp.line() != 0;
+        let frame = self.frame + mid_frame as u64;
+        if let Some((ref mut movie, ref mut start)) = self.movie {
+            match frame.checked_sub(*start) {
+                Some(recorded) if recorded <= movie.frames.len() as u64 => {
+                    movie.frames.truncate(recorded as usize);
+                }
+                _ => {
+                    movie.frames.clear();
+                    *start = frame;
+                }
+            }
+        }
+    }
+
+    // The movie's inputs replace the joypads, Reset and Pause until it runs out.
+    // The machine should be configured from the movie and freshly powered on.
+    // Fails if the movie was recorded with a different cartridge loaded.
+    pub fn play_movie(&mut self, movie: Movie) -> io::Result<()> {
+        if movie.rom_crc != self.rom_crc {
+            return Err(savestate::invalid(
+                "movie was recorded with a different ROM",
+            ));
+        }
+        self.playback = Some((movie, 0));
+        Ok(())
+    }
+
+    pub fn is_playing_movie(&self) -> bool {
+        self.playback.is_some()
+    }
+
+    // Frame 0 of the script is the next frame run.
+    pub fn play_script(&mut self, script: InputScript) {
+        self.script = Some((script, 0));
+    }
+
+    // Frames completed since power-on, or since the frame a rewind went back to.
+    pub fn frame_count(&self) -> u64 {
+        self.frame
+    }
+
+    // Keeps up to `capacity` snapshots, one every `interval` frames, plus the input of every frame since the oldest.
+    pub fn enable_rewind(&mut self, interval: u32, capacity: usize) {
+        self.rewind = Some(RewindBuffer::new(interval, capacity));
+        self.restart_rewind();
+    }
+
+    // The history before a loaded state belongs to another timeline, so it is dropped.
+    pub(crate) fn restart_rewind(&mut self) {
+        match self.rewind {
+            Some(ref mut rewind) => rewind.clear(),
+            None => return,
+        }
+        if self.line_end.is_none() && self.vdp.line() == 0 {
+            self.take_snapshot();
+        }
+    }
+
+    // Snapshots are taken between frames, before the host changes the input for the next one.
+    fn take_snapshot(&mut self) {
+        let state = self.save_state();
+        if let Some(ref mut rewind) = self.rewind {
+            rewind.push_snapshot(self.frame, state);
+        }
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    pub fn rewind_buffer(&self) -> Option<&RewindBuffer> {
+        self.rewind.as_ref()
+    }
+
+    // Goes back to the start of the previous frame, or of the current one when stopped mid-frame.
+    // The nearest older snapshot is loaded and the frames after it are run again silently with their recorded input
+    // and peripherals.
+    // A movie being recorded is cut back to the target frame; scripts and movie playback are not rewound,
+    // and the scanline hook does not see the replayed lines.
+    pub fn rewind_frame(&mut self) -> bool {
+        let target = if self.line_end.is_some() || self.vdp.line() != 0 {
+            self.frame
+        } else if self.frame > 0 {
+            self.frame - 1
+        } else {
+            return false;
+        };
+        let state = match self.rewind.as_mut().and_then(|r| r.rewind_to(target)) {
+            Some((_, state)) => state,
+            None => return false,
+        };
+        if self.read_state(&state).is_err() {
+            return false;
+        }
+        let hook = self.vdp.take_scanline_hook();
+        self.replaying = true;
+        self.silent = true;
+        while self.frame < target {
+            self.step();
+        }
+        self.replaying = false;
+        self.silent = false;
+        self.vdp.restore_scanline_hook(hook);
+        self.restart_audio();
+        self.truncate_movie();
+        true
+    }
+
+    fn update_input(&mut self) {
+        let frame = self.frame;
+        let replayed = match self.rewind {
+            Some(ref rewind) if self.replaying => rewind.input(frame),
+            _ => None,
+        };
+        match replayed {
+            Some(input) => self.apply_frame_input(input),
+            None => self.play_input(),
+        }
+        let input = FrameInput {
+            pads: [self.controllers.buttons(0), self.controllers.buttons(1)],
+            peripherals: [
+                self.controllers.peripheral(0),
+                self.controllers.peripheral(1),
+            ],
+            reset: self.controllers.is_reset_pressed(),
+            pause: self.pause_pressed,
+        };
+        if !self.replaying {
+            if let Some((ref mut movie, _)) = self.movie {
+                movie.frames.push(input);
+            }
+        }
+        if let Some(ref mut rewind) = self.rewind {
+            rewind.push_input(frame, input);
+        }
+        self.pause_pressed = false;
+    }
+
+    fn play_input(&mut self) {
+        if let Some((script, frame)) = self.script.take() {
+            for action in script.actions_at(frame) {
+                self.apply_action(action);
+            }
+            self.script = Some((script, frame + 1));
+        }
+        let input = match self.playback {
+            Some((ref movie, ref mut frame)) => {
+                *frame += 1;
+                Some(movie.frames.get(*frame - 1).cloned())
+            }
+            None => None,
+        };
+        match input {
+            Some(Some(input)) => self.apply_frame_input(input),
+            Some(None) => self.playback = None,
+            None => {}
+        }
+    }
+
+    fn apply_frame_input(&mut self, input: FrameInput) {
+        self.controllers.set_buttons(0, input.pads[0]);
+        self.controllers.set_buttons(1, input.pads[1]);
+        self.controllers.connect(0, input.peripherals[0]);
+        self.controllers.connect(1, input.peripherals[1]);
+        self.controllers.set_reset(input.reset);
+        if input.pause {
+            self.press_pause();
+        }
+    }
+
+    fn apply_action(&mut self, action: Action) {
+        match action {
+            Action::Press(port, button) | Action::Release(port, button) => {
+                let mut buttons = self.controllers.buttons(port);
+                button.set(&mut buttons, action == Action::Press(port, button));
+                self.controllers.set_buttons(port, buttons);
+            }
+            Action::Reset(pressed) => self.controllers.set_reset(pressed),
+            Action::Pause => self.press_pause(),
+        }
+    }
+
+    pub fn connect(&mut self, port: usize, peripheral: Peripheral) {
+        self.controllers.connect(port, peripheral);
+    }
+
+    // A phaser sees light on the lines just after its aim row once that row has been drawn bright.
+    // Starting to see it pulls TH low, and that falling edge latches the H counter at the aim point.
+    fn sense_light_phasers(&mut self) {
+        for port in 0..2 {
+            let phaser = match self.controllers.peripheral(port) {
+                Peripheral::LightPhaser(phaser) => phaser,
+                _ => continue,
+            };
+            // Lines before this one have already been drawn in the current frame.
+            let line = self.vdp.line();
+            let (x, y) = (phaser.x as usize, phaser.y as usize);
+            let lit = x < vdp::SCREEN_WIDTH
+                && y < self.vdp.active_height()
+                && line > phaser.y
+                && line <= phaser.y + controller::PHASER_RADIUS
+                && is_bright(self.vdp.screen_pixel(x, y));
+            let falling_edge = self.controllers.set_light(port, lit);
+            if falling_edge && self.controllers.th_is_input(port) {
+                self.vdp.latch_h_counter(phaser.x as u64);
+            }
+        }
+    }
+
+    pub fn set_reset_button(&mut self, pressed: bool) {
+        self.controllers.set_reset(pressed);
+    }
+
+    // Japanese consoles and the Mark III FM add-on.
+    pub fn attach_fm_unit(&mut self) {
+        self.fm = Some(Ym2413::new());
+    }
+
+    pub fn set_channel_muted(&mut self, channel: Channel, muted: bool) {
+        let cycle = self.cpu.cycles();
+        self.run_audio_until(cycle);
+        self.mixer.set_muted(channel, muted);
+    }
+
+    pub fn set_channel_solo(&mut self, channel: Channel, soloed: bool) {
+        let cycle = self.cpu.cycles();
+        self.run_audio_until(cycle);
+        self.mixer.set_solo(channel, soloed);
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        let cycle = self.cpu.cycles();
+        self.run_audio_until(cycle);
+        self.mixer.set_sample_rate(sample_rate, cycle);
+    }
+
+    // Silent frames still advance the chips but leave the mixer where it was.
+    pub(crate) fn run_audio_until(&mut self, cycle: u64) {
+        if self.silent {
+            self.psg.run_until(cycle);
+            if let Some(ref mut fm) = self.fm {
+                fm.run_until(cycle);
+            }
+            return;
+        }
+        self.mixer.run_until(cycle, &mut self.psg, &mut self.fm);
+    }
+
+    // Fills `out` with whole sample frames from the audio ring and returns how many values were written.
+    // Output the host does not pull in time is dropped once the ring is full.
+    pub fn take_audio(&mut self, out: &mut [i16]) -> usize {
+        let channels = self.mixer.channels() as usize;
+        let whole = out.len() - out.len() % channels;
+        match self.audio_reader {
+            Some(ref mut reader) => reader.pop(&mut out[..whole]),
+            None => 0,
+        }
+    }
+
+    // Hands the reading end of the audio ring to another thread; take_audio returns nothing afterwards.
+    pub fn detach_audio(&mut self) -> Option<Consumer> {
+        self.audio_reader.take()
+    }
+
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        let mut samples = Vec::new();
+        let mut chunk = [0; 1024];
+        loop {
+            let count = self.take_audio(&mut chunk);
+            if count == 0 {
+                return samples;
+            }
+            samples.extend_from_slice(&chunk[..count]);
+        }
+    }
+
+    pub fn start_recording<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let out = BufWriter::new(File::create(path)?);
+        self.recorder = Some(WavWriter::new(
+            out,
+            self.mixer.sample_rate(),
+            self.mixer.channels(),
+        )?);
+        self.recording_error = None;
+        Ok(())
+    }
+
+    // Writes psg0.wav to psg3.wav, and fm0.wav to fm8.wav with the FM unit attached, into `directory`.
+    pub fn start_channel_recording<P: AsRef<Path>>(&mut self, directory: P) -> io::Result<()> {
+        let directory = directory.as_ref();
+        fs::create_dir_all(directory)?;
+        let mut channels = Channel::psg_channels();
+        if self.fm.is_some() {
+            channels.extend(Channel::fm_channels());
+        }
+        let mut recorders = Vec::new();
+        for channel in &channels {
+            let out = BufWriter::new(File::create(directory.join(format!("{}.wav", channel)))?);
+            recorders.push(WavWriter::new(out, self.mixer.sample_rate(), 1)?);
+        }
+        let cycle = self.cpu.cycles();
+        self.run_audio_until(cycle);
+        self.mixer.end_frame(cycle);
+        self.mixer.split_channels(&channels);
+        self.channel_recorders = recorders;
+        self.recording_error = None;
+        Ok(())
+    }
+
+    // Stops both the mixed and the per-channel recordings, finishing every file even if one fails.
+    // Reports the first error, including one that made recording stop early.
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        self.mixer.split_channels(&[]);
+        let channel_recorders = ::std::mem::take(&mut self.channel_recorders);
+        let mut error = self.recording_error.take();
+        for recorder in self.recorder.take().into_iter().chain(channel_recorders) {
+            if let Err(e) = recorder.finish() {
+                error = error.or(Some(e));
+            }
+        }
+        match error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    // Logs every PSG and FM register write from now on.
+    // The log opens with the chips' current registers, so it can start mid-run.
+    pub fn start_vgm_logging<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let out = BufWriter::new(File::create(path)?);
+        let cycle = self.cpu.cycles();
+        let mut vgm = VgmWriter::new(out, self.vdp.video_standard(), cycle, self.fm.is_some());
+        for value in self.psg.register_writes() {
+            vgm.log(cycle, VgmCommand::Psg(value));
+        }
+        if self.vdp.model() == Model::GameGear {
+            vgm.log(cycle, VgmCommand::GameGearStereo(self.psg.panning()));
+        }
+        if let Some(ref fm) = self.fm {
+            for (register, value) in fm.register_writes() {
+                vgm.log(cycle, VgmCommand::Fm(register, value));
+            }
+        }
+        self.vgm = Some(vgm);
+        Ok(())
+    }
+
+    pub fn stop_vgm_logging(&mut self) -> io::Result<()> {
+        if let Some(vgm) = self.vgm.take() {
+            vgm.finish(self.cpu.cycles())?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn log_vgm(&mut self, command: VgmCommand) {
+        let cycle = self.cpu.cycles();
+        if self.silent {
+            return;
+        }
+        if let Some(ref mut vgm) = self.vgm {
+            vgm.log(cycle, command);
+        }
+    }
+
+    // Feeds one video frame's worth of VGM writes to the sound chips while the CPU stays idle.
+    pub fn play_vgm_frame(&mut self, player: &mut VgmPlayer) {
+        let now = self.cpu.cycles();
+        let lines = self.vdp.video_standard().lines_per_frame() as u64;
+        let end = now + vdp::CYCLES_PER_LINE * lines;
+        let clock = self.mixer.clock();
+        while let Some((cycle, command)) = player.next_write(clock, now, end) {
+            self.cpu.idle_until(cycle);
+            match command {
+                VgmCommand::Psg(value) => self.write_port(0x7F, value),
+                VgmCommand::GameGearStereo(value) => self.write_port(0x06, value),
+                VgmCommand::Fm(register, value) => {
+                    self.write_port(0xF0, register);
+                    self.write_port(0xF1, value);
+                }
+                _ => {}
+            }
+        }
+        self.cpu.idle_until(end);
+        self.run_audio_until(end);
+        self.capture_audio(end);
+    }
+
+    // `cycle` is where the sound chips were run to; the last instruction of a frame may have run past it.
+    fn capture_audio(&mut self, cycle: u64) -> Vec<i16> {
+        if self.silent {
+            return Vec::new();
+        }
+        self.mixer.end_frame(cycle);
+        let samples = self.mixer.take_samples();
+        let failed = match self.recorder {
+            Some(ref mut recorder) => recorder.write_samples(&samples).err(),
+            None => None,
+        };
+        if failed.is_some() {
+            self.recorder = None;
+            self.recording_error = failed;
+        }
+        let channel_samples = self.mixer.take_channel_samples();
+        let failed = self
+            .channel_recorders
+            .iter_mut()
+            .zip(&channel_samples)
+            .filter_map(|(recorder, (_, samples))| recorder.write_samples(samples).err())
+            .next();
+        if failed.is_some() {
+            self.channel_recorders.clear();
+            self.mixer.split_channels(&[]);
+            self.recording_error = failed;
+        }
+        let channels = self.mixer.channels() as usize;
+        let free = self.audio.free();
+        let whole = samples.len().min(free - free % channels);
+        self.audio.push(&samples[..whole]);
+        samples
+    }
+
+    pub fn on_scanline<F: FnMut(&ScanlineEvent) + 'static>(&mut self, hook: F) {
+        self.vdp.set_scanline_hook(hook);
+    }
+
+    pub fn save_screenshot<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        image::save(&self.vdp.frame(), path)
+    }
+
+    pub fn dump_graphics<P: AsRef<Path>>(&self, directory: P) -> io::Result<()> {
+        let directory = directory.as_ref();
+        fs::create_dir_all(directory)?;
+        image::save(
+            &self.vdp.tile_sheet(0),
+            directory.join("tiles_background.png"),
+        )?;
+        image::save(&self.vdp.tile_sheet(1), directory.join("tiles_sprite.png"))?;
+        image::save(&self.vdp.name_table_map(), directory.join("name_table.png"))?;
+        image::save(&self.vdp.sprite_map(), directory.join("sprites.png"))?;
+        image::save(&self.vdp.palette_swatches(), directory.join("palette.png"))?;
+        let mut listing = File::create(directory.join("sprites.txt"))?;
+        for sprite in self.vdp.sprite_table() {
+            writeln!(listing, "{}", sprite)?;
+        }
+        Ok(())
+    }
+}
+
+fn is_bright(rgb: u32) -> bool {
+    let sum = ((rgb >> 16) & 0xFF) + ((rgb >> 8) & 0xFF) + (rgb & 0xFF);
+    sum >= 0x180
 }
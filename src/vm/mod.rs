@@ -1,4 +1,14 @@
+pub mod audio;
 pub mod cpu;
 pub mod instructions;
+pub mod io;
 pub mod machine;
+pub mod movie;
 pub mod ram;
+pub mod region;
+pub mod rewind;
+pub mod run;
+pub mod savestate;
+pub mod script;
+pub mod snapshot;
+pub mod video;
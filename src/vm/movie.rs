@@ -0,0 +1,138 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use vm::io::controller::{Buttons, Peripheral};
+use vm::region::{Region, VideoStandard};
+use vm::savestate::{StateReader, StateWriter};
+use vm::video::model::Model;
+
+const MAGIC: &[u8; 8] = b"SMSMOVIE";
+const VERSION: u8 = 3;
+const RESET: u8 = 0x01;
+const PAUSE: u8 = 0x02;
+
+// What the player did during one frame: both joypads, Reset and Pause, and what was plugged into
+// each port with its aim, position or trigger state.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct FrameInput {
+    pub pads: [Buttons; 2],
+    pub peripherals: [Peripheral; 2],
+    pub reset: bool,
+    pub pause: bool,
+}
+
+// Per-frame input from power-on, with the configuration needed to replay it.
+// `rom_crc` is the CRC-32 of the cartridge the movie was recorded with.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Movie {
+    pub model: Model,
+    pub region: Region,
+    pub video_standard: VideoStandard,
+    pub fm: bool,
+    pub rom_crc: u32,
+    pub frames: Vec<FrameInput>,
+}
+
+impl Movie {
+    pub fn new(
+        model: Model,
+        region: Region,
+        video_standard: VideoStandard,
+        fm: bool,
+        rom_crc: u32,
+    ) -> Movie {
+        Movie {
+            model,
+            region,
+            video_standard,
+            fm,
+            rom_crc,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Movie> {
+        Movie::read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write(&mut out)?;
+        out.flush()
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[
+            VERSION,
+            self.model.code(),
+            self.region.code(),
+            (self.video_standard == VideoStandard::Pal) as u8,
+            self.fm as u8,
+        ])?;
+        out.write_all(&self.rom_crc.to_le_bytes())?;
+        out.write_all(&(self.frames.len() as u32).to_le_bytes())?;
+        // Peripherals use the save state encoding, so frames vary in length.
+        let mut frames = StateWriter::new();
+        for frame in &self.frames {
+            let mut flags = 0;
+            if frame.reset {
+                flags |= RESET;
+            }
+            if frame.pause {
+                flags |= PAUSE;
+            }
+            frames.write_bytes(&[frame.pads[0].bits(), frame.pads[1].bits(), flags]);
+            frame.peripherals[0].save_state(&mut frames);
+            frame.peripherals[1].save_state(&mut frames);
+        }
+        out.write_all(&frames.into_bytes())
+    }
+
+    pub fn read<R: Read>(mut input: R) -> io::Result<Movie> {
+        let mut header = [0; 21];
+        input.read_exact(&mut header)?;
+        if &header[0..8] != MAGIC {
+            return Err(invalid("not a movie file"));
+        }
+        if header[8] != VERSION {
+            return Err(invalid("unsupported movie version"));
+        }
+        let model = Model::from_code(header[9]).ok_or_else(|| invalid("unknown model in movie"))?;
+        let region =
+            Region::from_code(header[10]).ok_or_else(|| invalid("unknown region in movie"))?;
+        let video_standard = if header[11] != 0 {
+            VideoStandard::Pal
+        } else {
+            VideoStandard::Ntsc
+        };
+        let rom_crc = u32::from_le_bytes([header[13], header[14], header[15], header[16]]);
+        let mut movie = Movie::new(model, region, video_standard, header[12] != 0, rom_crc);
+        let count = u32::from_le_bytes([header[17], header[18], header[19], header[20]]);
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        let mut frames = StateReader::new(&data);
+        for _ in 0..count {
+            let mut frame = [0; 3];
+            frames.read_bytes(&mut frame)?;
+            movie.frames.push(FrameInput {
+                pads: [Buttons::from_bits(frame[0]), Buttons::from_bits(frame[1])],
+                peripherals: [
+                    Peripheral::load_state(&mut frames)?,
+                    Peripheral::load_state(&mut frames)?,
+                ],
+                reset: frame[2] & RESET != 0,
+                pause: frame[2] & PAUSE != 0,
+            });
+        }
+        if !frames.is_empty() {
+            return Err(invalid("unexpected data after movie"));
+        }
+        Ok(movie)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -1,5 +1,8 @@
+use std::io;
 use vm::cpu::registers::Registers;
+use vm::savestate::{StateReader, StateWriter};
 
+#[derive(Clone)]
 pub struct Memory {
     data: [u8; 65536],
 }
@@ -28,4 +31,12 @@ impl Memory {
         self.write_u8(address, low);
         self.write_u8(address + 1, high);
     }
+
+    pub(crate) fn save_state(&self, out: &mut StateWriter) {
+        out.write_bytes(&self.data);
+    }
+
+    pub(crate) fn load_state(&mut self, input: &mut StateReader) -> io::Result<()> {
+        input.read_bytes(&mut self.data)
+    }
 }
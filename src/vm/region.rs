@@ -0,0 +1,86 @@
+use vm::video::vdp;
+
+pub const NTSC_CLOCK: u32 = 3_579_545;
+pub const PAL_CLOCK: u32 = 3_546_893;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Region {
+    Japan,
+    Usa,
+    Europe,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum VideoStandard {
+    Ntsc,
+    Pal,
+}
+
+impl Region {
+    pub fn code(self) -> u8 {
+        match self {
+            Region::Japan => 0,
+            Region::Usa => 1,
+            Region::Europe => 2,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Region> {
+        match code {
+            0 => Some(Region::Japan),
+            1 => Some(Region::Usa),
+            2 => Some(Region::Europe),
+            _ => None,
+        }
+    }
+
+    pub fn is_japanese(self) -> bool {
+        self == Region::Japan
+    }
+
+    pub fn video_standard(self) -> VideoStandard {
+        match self {
+            Region::Europe => VideoStandard::Pal,
+            _ => VideoStandard::Ntsc,
+        }
+    }
+}
+
+impl VideoStandard {
+    pub fn cpu_clock(self) -> u32 {
+        match self {
+            VideoStandard::Ntsc => NTSC_CLOCK,
+            VideoStandard::Pal => PAL_CLOCK,
+        }
+    }
+
+    pub fn lines_per_frame(self) -> u16 {
+        match self {
+            VideoStandard::Ntsc => vdp::LINES_PER_FRAME,
+            VideoStandard::Pal => vdp::PAL_LINES_PER_FRAME,
+        }
+    }
+
+    pub fn frames_per_second(self) -> u32 {
+        match self {
+            VideoStandard::Ntsc => 60,
+            VideoStandard::Pal => 50,
+        }
+    }
+}
+
+// The "TMR SEGA" header's region nibble. Export headers cannot tell the USA from Europe,
+// and many Japanese Master System cartridges have no header at all.
+pub fn detect(rom: &[u8]) -> Option<Region> {
+    for &base in &[0x7FF0, 0x3FF0, 0x1FF0] {
+        if rom.len() < base + 0x10 || &rom[base..base + 8] != b"TMR SEGA" {
+            continue;
+        }
+        return match rom[base + 0x0F] >> 4 {
+            3 | 5 => Some(Region::Japan),
+            4 | 6 | 7 => Some(Region::Usa),
+            _ => None,
+        };
+    }
+    None
+}
@@ -0,0 +1,179 @@
+use std::collections::VecDeque;
+use vm::movie::FrameInput;
+
+// Snapshots taken every `interval` frames; only the newest is kept whole and each older one is stored
+// as the run-length encoded XOR against the one after it, which is mostly zeros.
+pub struct RewindBuffer {
+    interval: u32,
+    capacity: usize,
+    newest: Option<(u64, Vec<u8>)>,
+    older: VecDeque<(u64, Vec<u8>)>,
+    first_input: u64,
+    inputs: VecDeque<FrameInput>,
+}
+
+impl RewindBuffer {
+    pub fn new(interval: u32, capacity: usize) -> RewindBuffer {
+        RewindBuffer {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            newest: None,
+            older: VecDeque::new(),
+            first_input: 0,
+            inputs: VecDeque::new(),
+        }
+    }
+
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    pub fn len(&self) -> usize {
+        self.older.len() + self.newest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    // The first frame that can still be returned to.
+    pub fn oldest_frame(&self) -> Option<u64> {
+        match self.older.front() {
+            Some(&(frame, _)) => Some(frame),
+            None => self.newest.as_ref().map(|&(frame, _)| frame),
+        }
+    }
+
+    pub fn wants_snapshot(&self, frame: u64) -> bool {
+        frame.is_multiple_of(self.interval as u64)
+            && self
+                .newest
+                .as_ref()
+                .is_none_or(|&(newest, _)| newest < frame)
+    }
+
+    pub fn push_snapshot(&mut self, frame: u64, state: Vec<u8>) {
+        if let Some((previous, full)) = self.newest.take() {
+            self.older
+                .push_back((previous, encode_delta(&full, &state)));
+        }
+        self.newest = Some((frame, state));
+        if self.len() > self.capacity {
+            self.older.pop_front();
+            let oldest = self.oldest_frame().unwrap_or(frame);
+            while self.first_input < oldest && self.inputs.pop_front().is_some() {
+                self.first_input += 1;
+            }
+        }
+    }
+
+    // Inputs are only kept from the oldest snapshot onwards, so replays can start from any of them.
+    pub fn push_input(&mut self, frame: u64, input: FrameInput) {
+        if self.inputs.is_empty() {
+            self.first_input = frame;
+        }
+        if frame == self.first_input + self.inputs.len() as u64 {
+            self.inputs.push_back(input);
+        }
+    }
+
+    pub fn input(&self, frame: u64) -> Option<FrameInput> {
+        frame
+            .checked_sub(self.first_input)
+            .and_then(|index| self.inputs.get(index as usize))
+            .cloned()
+    }
+
+    // Forgets everything after `frame` and returns the newest snapshot at or before it.
+    pub fn rewind_to(&mut self, frame: u64) -> Option<(u64, Vec<u8>)> {
+        if self.oldest_frame().is_none_or(|oldest| oldest > frame) {
+            return None;
+        }
+        loop {
+            match self.newest {
+                Some((newest, _)) if newest <= frame => break,
+                Some(_) => {}
+                None => return None,
+            }
+            let (_, full) = self.newest.take().unwrap();
+            self.newest = self
+                .older
+                .pop_back()
+                .map(|(previous, delta)| (previous, decode_delta(&delta, &full)));
+        }
+        let keep = frame.saturating_sub(self.first_input) as usize;
+        self.inputs.truncate(keep);
+        self.newest.clone()
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.older.clear();
+        self.inputs.clear();
+    }
+}
+
+// The old length, then pairs of (run of unchanged bytes, count of changed bytes, their XOR values).
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_varint(&mut out, old.len() as u64);
+    let xor: Vec<u8> = old
+        .iter()
+        .enumerate()
+        .map(|(i, &byte)| byte ^ new.get(i).cloned().unwrap_or(0))
+        .collect();
+    let mut i = 0;
+    while i < xor.len() {
+        let same = xor[i..].iter().take_while(|&&byte| byte == 0).count();
+        let changed = xor[i + same..]
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .count();
+        write_varint(&mut out, same as u64);
+        write_varint(&mut out, changed as u64);
+        out.extend_from_slice(&xor[i + same..i + same + changed]);
+        i += same + changed;
+    }
+    out
+}
+
+fn decode_delta(delta: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut position = 0;
+    let length = read_varint(delta, &mut position) as usize;
+    let mut old: Vec<u8> = (0..length)
+        .map(|i| new.get(i).cloned().unwrap_or(0))
+        .collect();
+    let mut i = 0;
+    while position < delta.len() {
+        i += read_varint(delta, &mut position) as usize;
+        let changed = read_varint(delta, &mut position) as usize;
+        for byte in &mut old[i..i + changed] {
+            *byte ^= delta[position];
+            position += 1;
+        }
+        i += changed;
+    }
+    old
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> u64 {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*position];
+        *position += 1;
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
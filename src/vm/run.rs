@@ -0,0 +1,65 @@
+use vm::machine::{Machine, Step};
+
+// Checked after every instruction and every finished line; budgets count from the run_until call.
+pub enum StopCondition {
+    Halted,
+    Cycles(u64),
+    Instructions(u64),
+    Address(u16),
+    Frames(u32),
+    Memory(u16, u8),
+    Predicate(Box<dyn Fn(&Machine) -> bool>),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum StopReason {
+    Halted,
+    Cycles,
+    Instructions,
+    Address(u16),
+    Frames,
+    Memory(u16, u8),
+    Predicate,
+}
+
+impl Machine {
+    // Runs the whole system until one of the conditions holds, reporting the first in the list that does.
+    // Unlike start, a halted CPU keeps idling and waiting for interrupts unless Halted is given.
+    pub fn run_until(&mut self, conditions: &[StopCondition]) -> StopReason {
+        let start = self.cpu.cycles();
+        let mut instructions = 0;
+        let mut frames = 0;
+        loop {
+            match self.step() {
+                Step::Instruction => instructions += 1,
+                Step::Interrupt | Step::Line => {}
+                Step::Frame(_) => frames += 1,
+            }
+            for condition in conditions {
+                let reason = match *condition {
+                    StopCondition::Halted if self.cpu.is_halted() => StopReason::Halted,
+                    StopCondition::Cycles(budget) if self.cpu.cycles() - start >= budget => {
+                        StopReason::Cycles
+                    }
+                    StopCondition::Instructions(budget) if instructions >= budget => {
+                        StopReason::Instructions
+                    }
+                    StopCondition::Address(address)
+                        if self.cpu.state.program_counter == address =>
+                    {
+                        StopReason::Address(address)
+                    }
+                    StopCondition::Frames(count) if frames >= count => StopReason::Frames,
+                    StopCondition::Memory(address, value) if self.ram.read_u8(address) == value => {
+                        StopReason::Memory(address, value)
+                    }
+                    StopCondition::Predicate(ref predicate) if predicate(self) => {
+                        StopReason::Predicate
+                    }
+                    _ => continue,
+                };
+                return reason;
+            }
+        }
+    }
+}
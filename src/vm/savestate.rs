@@ -0,0 +1,209 @@
+use std::io;
+use vm::audio::psg::Psg;
+use vm::audio::ym2413::Ym2413;
+use vm::cpu::processor::Processor;
+use vm::io::controller::Controllers;
+use vm::machine::Machine;
+use vm::ram::memory::Memory;
+use vm::region::Region;
+use vm::video::model::Model;
+use vm::video::vdp::Vdp;
+
+const MAGIC: &[u8; 8] = b"SMSSTATE";
+const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+pub const VERSION: u8 = 2;
+
+// Little-endian fields appended in the order each component saves them.
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_i32(&mut self, value: i32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        let mut value = [0; 1];
+        self.read_bytes(&mut value)?;
+        Ok(value[0])
+    }
+
+    pub fn read_bool(&mut self) -> io::Result<bool> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> io::Result<u16> {
+        let mut value = [0; 2];
+        self.read_bytes(&mut value)?;
+        Ok(u16::from_le_bytes(value))
+    }
+
+    pub fn read_u32(&mut self) -> io::Result<u32> {
+        let mut value = [0; 4];
+        self.read_bytes(&mut value)?;
+        Ok(u32::from_le_bytes(value))
+    }
+
+    pub fn read_i32(&mut self) -> io::Result<i32> {
+        let mut value = [0; 4];
+        self.read_bytes(&mut value)?;
+        Ok(i32::from_le_bytes(value))
+    }
+
+    pub fn read_u64(&mut self) -> io::Result<u64> {
+        let mut value = [0; 8];
+        self.read_bytes(&mut value)?;
+        Ok(u64::from_le_bytes(value))
+    }
+
+    pub fn read_bytes(&mut self, out: &mut [u8]) -> io::Result<()> {
+        if self.data.len() < out.len() {
+            return Err(invalid("save state is truncated"));
+        }
+        let (bytes, rest) = self.data.split_at(out.len());
+        out.copy_from_slice(bytes);
+        self.data = rest;
+        Ok(())
+    }
+}
+
+pub fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl Machine {
+    // Everything that affects emulation, but not host-side settings such as mute, recordings or movies.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = StateWriter::new();
+        out.write_bytes(MAGIC);
+        out.write_u8(VERSION);
+        out.write_u8(self.vdp.model().code());
+        out.write_u8(self.region().code());
+        out.write_bool(self.fm.is_some());
+        self.cpu.save_state(&mut out);
+        self.ram.save_state(&mut out);
+        self.vdp.save_state(&mut out);
+        self.psg.save_state(&mut out);
+        if let Some(ref fm) = self.fm {
+            fm.save_state(&mut out);
+        }
+        self.controllers.save_state(&mut out);
+        self.save_scheduler_state(&mut out);
+        out.into_bytes()
+    }
+
+    // FNV-1a over the save state, so two runs that agree on every emulated bit agree on the hash.
+    pub fn state_hash(&self) -> u64 {
+        self.save_state().iter().fold(FNV_OFFSET, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+        })
+    }
+
+    // Leaves the machine untouched unless the whole state could be read.
+    // Audio resumes from the loaded cycle and pending output is dropped, rewinding starts over from here,
+    // and a movie being recorded is cut back to the loaded frame.
+    pub fn load_state(&mut self, data: &[u8]) -> io::Result<()> {
+        self.read_state(data)?;
+        self.restart_rewind();
+        self.truncate_movie();
+        Ok(())
+    }
+
+    pub(crate) fn read_state(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut input = StateReader::new(data);
+        let mut magic = [0; 8];
+        input.read_bytes(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a save state"));
+        }
+        if input.read_u8()? != VERSION {
+            return Err(invalid("unsupported save state version"));
+        }
+        if Model::from_code(input.read_u8()?) != Some(self.vdp.model()) {
+            return Err(invalid("save state is for a different console"));
+        }
+        let region = Region::from_code(input.read_u8()?)
+            .ok_or_else(|| invalid("unknown region in save state"))?;
+        let has_fm = input.read_bool()?;
+
+        let mut cpu = Processor::new();
+        cpu.load_state(&mut input)?;
+        let mut ram = Memory::new();
+        ram.load_state(&mut input)?;
+        let mut vdp = Vdp::new(self.vdp.model());
+        vdp.load_state(&mut input)?;
+        let mut psg = Psg::new();
+        psg.load_state(&mut input)?;
+        let fm = if has_fm {
+            let mut fm = Ym2413::new();
+            fm.load_state(&mut input)?;
+            Some(fm)
+        } else {
+            None
+        };
+        let mut controllers = Controllers::new();
+        controllers.load_state(&mut input)?;
+        let (pause_pressed, line_end, frame) = Machine::load_scheduler_state(&mut input)?;
+        if !input.is_empty() {
+            return Err(invalid("unexpected data after save state"));
+        }
+
+        vdp.take_scanline_hook_from(&mut self.vdp);
+        self.cpu = cpu;
+        self.ram = ram;
+        self.vdp = vdp;
+        self.psg = psg;
+        self.fm = fm;
+        self.controllers = controllers;
+        self.restore_scheduler_state(region, pause_pressed, line_end, frame);
+        Ok(())
+    }
+}
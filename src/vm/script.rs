@@ -0,0 +1,122 @@
+use vm::io::controller::Buttons;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Button {
+    Up,
+    Down,
+    Left,
+    Right,
+    Button1,
+    Button2,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Action {
+    Press(usize, Button),
+    Release(usize, Button),
+    Reset(bool),
+    Pause,
+}
+
+// Timed input for headless runs, written as `frame 120: press 1; frame 125: release 1`.
+// Statements are separated by `;` or new lines and `#` starts a comment.
+// Buttons are up, down, left, right, 1 and 2, prefixed with `p2.` for the second pad;
+// `press reset`, `release reset` and `pause` work the console buttons.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct InputScript {
+    events: Vec<(u32, Action)>,
+}
+
+impl InputScript {
+    pub fn parse(text: &str) -> Result<InputScript, String> {
+        let mut events = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            for statement in line.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+                let event = parse_statement(statement)
+                    .map_err(|e| format!("line {}: {}", number + 1, e))?;
+                events.push(event);
+            }
+        }
+        // Stable, so actions on the same frame keep their written order.
+        events.sort_by_key(|&(frame, _)| frame);
+        Ok(InputScript { events })
+    }
+
+    pub fn events(&self) -> &[(u32, Action)] {
+        &self.events
+    }
+
+    pub fn actions_at(&self, frame: u32) -> impl Iterator<Item = Action> + '_ {
+        self.events
+            .iter()
+            .filter(move |&&(at, _)| at == frame)
+            .map(|&(_, action)| action)
+    }
+
+    pub fn last_frame(&self) -> Option<u32> {
+        self.events.last().map(|&(frame, _)| frame)
+    }
+}
+
+impl Button {
+    pub fn set(self, buttons: &mut Buttons, pressed: bool) {
+        match self {
+            Button::Up => buttons.up = pressed,
+            Button::Down => buttons.down = pressed,
+            Button::Left => buttons.left = pressed,
+            Button::Right => buttons.right = pressed,
+            Button::Button1 => buttons.button1 = pressed,
+            Button::Button2 => buttons.button2 = pressed,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Button> {
+        match name {
+            "up" => Some(Button::Up),
+            "down" => Some(Button::Down),
+            "left" => Some(Button::Left),
+            "right" => Some(Button::Right),
+            "1" => Some(Button::Button1),
+            "2" => Some(Button::Button2),
+            _ => None,
+        }
+    }
+}
+
+fn parse_statement(statement: &str) -> Result<(u32, Action), String> {
+    let rest = statement
+        .strip_prefix("frame")
+        .ok_or_else(|| format!("expected 'frame N: ...' in '{}'", statement))?;
+    let mut parts = rest.splitn(2, ':');
+    let frame = parts.next().unwrap_or("").trim();
+    let frame = frame
+        .parse()
+        .map_err(|_| format!("invalid frame number '{}'", frame))?;
+    let words: Vec<&str> = parts.next().unwrap_or("").split_whitespace().collect();
+    let action = match words.as_slice() {
+        ["pause"] => Action::Pause,
+        ["press", "reset"] => Action::Reset(true),
+        ["release", "reset"] => Action::Reset(false),
+        ["press", name] => {
+            let (port, button) = parse_button(name)?;
+            Action::Press(port, button)
+        }
+        ["release", name] => {
+            let (port, button) = parse_button(name)?;
+            Action::Release(port, button)
+        }
+        _ => return Err(format!("unknown action in '{}'", statement)),
+    };
+    Ok((frame, action))
+}
+
+fn parse_button(name: &str) -> Result<(usize, Button), String> {
+    let (port, button) = match name.strip_prefix("p2.") {
+        Some(button) => (1, button),
+        None => (0, name.strip_prefix("p1.").unwrap_or(name)),
+    };
+    Button::from_name(button)
+        .map(|button| (port, button))
+        .ok_or_else(|| format!("unknown button '{}'", name))
+}
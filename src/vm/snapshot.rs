@@ -0,0 +1,21 @@
+use vm::audio::psg::Psg;
+use vm::audio::ym2413::Ym2413;
+use vm::cpu::processor::Processor;
+use vm::io::controller::Controllers;
+use vm::ram::memory::Memory;
+use vm::video::vdp::Vdp;
+
+// A copy of the emulated state kept in memory, for restoring many times a second.
+// Unlike save states it is not meant to be stored, and restoring it leaves the audio output alone.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub(crate) cpu: Processor,
+    pub(crate) ram: Memory,
+    pub(crate) vdp: Vdp,
+    pub(crate) psg: Psg,
+    pub(crate) fm: Option<Ym2413>,
+    pub(crate) controllers: Controllers,
+    pub(crate) pause_pressed: bool,
+    pub(crate) line_end: Option<u64>,
+    pub(crate) frame: u64,
+}
@@ -0,0 +1,114 @@
+use std::fmt;
+use vm::video::frame::Frame;
+use vm::video::vdp::Vdp;
+
+const TILES_PER_ROW: usize = 32;
+const SWATCH_SIZE: usize = 16;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SpriteEntry {
+    pub index: usize,
+    pub x: u8,
+    pub y: u8,
+    pub pattern: u16,
+}
+
+impl fmt::Display for SpriteEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:2}: x={:3} y={:3} pattern={:3}",
+            self.index, self.x, self.y, self.pattern
+        )
+    }
+}
+
+impl Vdp {
+    // All 512 patterns, 32 per row, drawn with the background (0) or sprite (1) palette.
+    pub fn tile_sheet(&self, palette: usize) -> Frame {
+        let rows = 512 / TILES_PER_ROW;
+        let mut frame = Frame::new(TILES_PER_ROW * 8, rows * 8);
+        for pattern in 0..512 {
+            let left = (pattern % TILES_PER_ROW) * 8;
+            let top = (pattern / TILES_PER_ROW) * 8;
+            for row in 0..8 {
+                for column in 0..8 {
+                    let index = palette * 16 + self.tile_pixel(pattern, row, column) as usize;
+                    frame.pixels[(top + row) * frame.width + left + column] = self.colour(index);
+                }
+            }
+        }
+        frame
+    }
+
+    // The whole name table with no scrolling applied, including rows that are off screen.
+    pub fn name_table_map(&self) -> Frame {
+        let rows = self.name_table_rows();
+        let mut frame = Frame::new(32 * 8, rows * 8);
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                let entry = self.name_table_entry(x / 8, y / 8);
+                let index = self.entry_pixel(entry, x % 8, y % 8) as usize;
+                frame.pixels[y * frame.width + x] = self.colour(index);
+            }
+        }
+        frame
+    }
+
+    pub fn sprite_table(&self) -> Vec<SpriteEntry> {
+        let table = self.sprite_table_base();
+        let vram = self.vram();
+        let high_patterns = if self.register(6) & 0x04 != 0 { 256 } else { 0 };
+        let mut sprites = Vec::new();
+        for index in 0..64 {
+            let y = vram[table + index];
+            if self.active_height() == 192 && y == 0xD0 {
+                break;
+            }
+            sprites.push(SpriteEntry {
+                index,
+                x: vram[table + 0x80 + index * 2],
+                y,
+                pattern: vram[table + 0x81 + index * 2] as u16 + high_patterns,
+            });
+        }
+        sprites
+    }
+
+    // Every sprite at its position on a 256x256 canvas, ignoring the per-line limit and zoom.
+    pub fn sprite_map(&self) -> Frame {
+        let height = if self.register(1) & 0x02 != 0 { 16 } else { 8 };
+        let shift = if self.register(0) & 0x08 != 0 { 8 } else { 0 };
+        let mut frame = Frame::new(256, 256);
+        for sprite in self.sprite_table().iter().rev() {
+            let pattern = if height == 16 {
+                sprite.pattern as usize & !1
+            } else {
+                sprite.pattern as usize
+            };
+            for row in 0..height {
+                for column in 0..8 {
+                    let colour = self.tile_pixel(pattern + row / 8, row % 8, column);
+                    let x = (sprite.x as usize + column).wrapping_sub(shift);
+                    let y = (sprite.y as usize + 1 + row) % 256;
+                    if colour != 0 && x < 256 {
+                        frame.pixels[y * 256 + x] = self.colour(16 + colour as usize);
+                    }
+                }
+            }
+        }
+        frame
+    }
+
+    // One swatch per palette entry: background palette on top, sprite palette below.
+    pub fn palette_swatches(&self) -> Frame {
+        let mut frame = Frame::new(16 * SWATCH_SIZE, 2 * SWATCH_SIZE);
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                let index = (y / SWATCH_SIZE) * 16 + x / SWATCH_SIZE;
+                frame.pixels[y * frame.width + x] = self.colour(index);
+            }
+        }
+        frame
+    }
+}
@@ -0,0 +1,99 @@
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use vm::video::frame::Frame;
+
+// Picks the format from the file extension, defaulting to PNG.
+pub fn save<P: AsRef<Path>>(frame: &Frame, path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut out = BufWriter::new(File::create(path)?);
+    match path.extension() {
+        Some(extension) if extension == "ppm" => write_ppm(frame, &mut out),
+        _ => write_png(frame, &mut out),
+    }
+}
+
+pub fn write_ppm<W: Write>(frame: &Frame, out: &mut W) -> io::Result<()> {
+    write!(out, "P6\n{} {}\n255\n", frame.width, frame.height)?;
+    out.write_all(&rgb_bytes(frame))
+}
+
+pub fn write_png<W: Write>(frame: &Frame, out: &mut W) -> io::Result<()> {
+    let mut header = Vec::new();
+    header.extend_from_slice(&(frame.width as u32).to_be_bytes());
+    header.extend_from_slice(&(frame.height as u32).to_be_bytes());
+    // 8-bit RGB, default compression and filter, no interlacing.
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let row_length = frame.width * 3;
+    let mut raw = Vec::with_capacity((row_length + 1) * frame.height);
+    for row in rgb_bytes(frame).chunks(row_length) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    out.write_all(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A])?;
+    write_png_chunk(out, b"IHDR", &header)?;
+    write_png_chunk(out, b"IDAT", &zlib_stored(&raw))?;
+    write_png_chunk(out, b"IEND", &[])
+}
+
+fn rgb_bytes(frame: &Frame) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(frame.pixels.len() * 3);
+    for pixel in &frame.pixels {
+        bytes.push((pixel >> 16) as u8);
+        bytes.push((pixel >> 8) as u8);
+        bytes.push(*pixel as u8);
+    }
+    bytes
+}
+
+fn write_png_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let crc = crc32(kind.iter().chain(data.iter()));
+    out.write_all(&crc.to_be_bytes())
+}
+
+// Uncompressed deflate blocks keep the encoder trivial; screenshots are small.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let length = block.len() as u16;
+        out.push(if last { 0x01 } else { 0x00 });
+        out.extend_from_slice(&length.to_le_bytes());
+        out.extend_from_slice(&(!length).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+pub(crate) fn crc32<'a, I: Iterator<Item = &'a u8>>(bytes: I) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
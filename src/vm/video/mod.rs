@@ -0,0 +1,7 @@
+pub mod debug;
+pub mod frame;
+pub mod image;
+pub mod model;
+pub mod palette;
+pub mod scanline;
+pub mod vdp;
@@ -0,0 +1,53 @@
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Model {
+    // 315-5124
+    MasterSystem1,
+    // 315-5246
+    MasterSystem2,
+    // 315-5378
+    GameGear,
+}
+
+impl Model {
+    // How movies and save states store the model.
+    pub fn code(self) -> u8 {
+        match self {
+            Model::MasterSystem1 => 0,
+            Model::MasterSystem2 => 1,
+            Model::GameGear => 2,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Model> {
+        match code {
+            0 => Some(Model::MasterSystem1),
+            1 => Some(Model::MasterSystem2),
+            2 => Some(Model::GameGear),
+            _ => None,
+        }
+    }
+
+    pub fn cram_size(self) -> usize {
+        match self {
+            Model::GameGear => 64,
+            _ => 32,
+        }
+    }
+
+    pub fn supports_extended_height(self) -> bool {
+        self != Model::MasterSystem1
+    }
+
+    // The 315-5124 ANDs register 2 bit 0 into name table address bit 10.
+    pub fn masks_name_table(self) -> bool {
+        self == Model::MasterSystem1
+    }
+
+    // The 315-5124 only zooms the first four sprites of a line horizontally.
+    pub fn horizontally_zoomed_sprites(self) -> usize {
+        match self {
+            Model::MasterSystem1 => 4,
+            _ => 8,
+        }
+    }
+}
@@ -0,0 +1,17 @@
+// Colours are returned as 0x00RRGGBB.
+
+pub fn from_sms(value: u8) -> u32 {
+    let component = |shift: u8| ((value >> shift) & 0x03) as u32 * 0x55;
+    (component(0) << 16) | (component(2) << 8) | component(4)
+}
+
+pub fn from_game_gear(value: u16) -> u32 {
+    let component = |shift: u16| ((value >> shift) & 0x0F) as u32 * 0x11;
+    (component(0) << 16) | (component(4) << 8) | component(8)
+}
+
+// The Game Gear maps each 2-bit SMS component onto its 4-bit DAC input.
+pub fn sms_to_game_gear(value: u8) -> u16 {
+    let component = |shift: u8| ((value >> shift) & 0x03) as u16 * 5;
+    component(0) | (component(2) << 4) | (component(4) << 8)
+}
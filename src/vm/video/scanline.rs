@@ -0,0 +1,10 @@
+pub struct ScanlineEvent<'a> {
+    pub line: u16,
+    // Empty for lines outside the active display.
+    pub pixels: &'a [u32],
+    pub registers: [u8; 11],
+    pub line_interrupt: bool,
+    pub frame_interrupt: bool,
+}
+
+pub type ScanlineHook = Box<dyn FnMut(&ScanlineEvent)>;
@@ -0,0 +1,619 @@
+use std::io;
+use vm::cpu::alu;
+use vm::region::VideoStandard;
+use vm::savestate::{StateReader, StateWriter};
+use vm::video::frame::Frame;
+use vm::video::frame::Viewport;
+use vm::video::model::Model;
+use vm::video::palette;
+use vm::video::scanline::ScanlineEvent;
+use vm::video::scanline::ScanlineHook;
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const MAX_SCREEN_HEIGHT: usize = 240;
+pub const LINES_PER_FRAME: u16 = 262;
+pub const PAL_LINES_PER_FRAME: u16 = 313;
+pub const CYCLES_PER_LINE: u64 = 228;
+pub const PIXELS_PER_LINE: u64 = 342;
+pub const VRAM_SIZE: usize = 0x4000;
+
+const STATUS_FRAME_INTERRUPT: u8 = 0x80;
+const STATUS_SPRITE_OVERFLOW: u8 = 0x40;
+const STATUS_SPRITE_COLLISION: u8 = 0x20;
+
+pub struct Vdp {
+    model: Model,
+    video_standard: VideoStandard,
+    compatibility_mode: bool,
+    vram: [u8; VRAM_SIZE],
+    cram: [u8; 64],
+    registers: [u8; 11],
+    address: u16,
+    code: u8,
+    control_latch: Option<u8>,
+    cram_latch: u8,
+    read_buffer: u8,
+    status: u8,
+    line: u16,
+    line_counter: u8,
+    line_interrupt_pending: bool,
+    h_counter: u8,
+    screen: Vec<u32>,
+    scanline_hook: Option<ScanlineHook>,
+}
+
+// Copies are used for in-memory snapshots, so the host's scanline hook stays with the original.
+impl Clone for Vdp {
+    fn clone(&self) -> Vdp {
+        Vdp {
+            model: self.model,
+            video_standard: self.video_standard,
+            compatibility_mode: self.compatibility_mode,
+            vram: self.vram,
+            cram: self.cram,
+            registers: self.registers,
+            address: self.address,
+            code: self.code,
+            control_latch: self.control_latch,
+            cram_latch: self.cram_latch,
+            read_buffer: self.read_buffer,
+            status: self.status,
+            line: self.line,
+            line_counter: self.line_counter,
+            line_interrupt_pending: self.line_interrupt_pending,
+            h_counter: self.h_counter,
+            screen: self.screen.clone(),
+            scanline_hook: None,
+        }
+    }
+}
+
+impl Vdp {
+    pub fn new(model: Model) -> Vdp {
+        Vdp {
+            model,
+            video_standard: VideoStandard::Ntsc,
+            compatibility_mode: false,
+            vram: [0; VRAM_SIZE],
+            cram: [0; 64],
+            registers: [0; 11],
+            address: 0,
+            code: 0,
+            control_latch: None,
+            cram_latch: 0,
+            read_buffer: 0,
+            status: 0,
+            line: 0,
+            line_counter: 0,
+            line_interrupt_pending: false,
+            h_counter: 0,
+            screen: vec![0; SCREEN_WIDTH * MAX_SCREEN_HEIGHT],
+            scanline_hook: None,
+        }
+    }
+
+    pub fn set_scanline_hook<F: FnMut(&ScanlineEvent) + 'static>(&mut self, hook: F) {
+        self.scanline_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_scanline_hook(&mut self) {
+        self.scanline_hook = None;
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn set_video_standard(&mut self, standard: VideoStandard) {
+        self.video_standard = standard;
+        if self.line >= standard.lines_per_frame() {
+            self.line = 0;
+        }
+    }
+
+    pub fn video_standard(&self) -> VideoStandard {
+        self.video_standard
+    }
+
+    // A Game Gear running a Master System cartridge uses the SMS palette
+    // layout and shows the whole SMS display area.
+    pub fn set_compatibility_mode(&mut self, enabled: bool) {
+        self.compatibility_mode = enabled && self.model == Model::GameGear;
+    }
+
+    pub fn is_compatibility_mode(&self) -> bool {
+        self.compatibility_mode
+    }
+
+    pub fn register(&self, index: usize) -> u8 {
+        self.registers[index]
+    }
+
+    pub fn vram(&self) -> &[u8] {
+        &self.vram
+    }
+
+    pub fn cram(&self) -> &[u8] {
+        &self.cram[..self.model.cram_size()]
+    }
+
+    pub fn line(&self) -> u16 {
+        self.line
+    }
+
+    pub fn write_control(&mut self, value: u8) {
+        match self.control_latch.take() {
+            None => {
+                self.control_latch = Some(value);
+                self.address = (self.address & 0x3F00) | value as u16;
+            }
+            Some(low) => {
+                self.code = value >> 6;
+                self.address = alu::get_word(value & 0x3F, low);
+                match self.code {
+                    0 => {
+                        self.read_buffer = self.vram[self.address as usize];
+                        self.increment_address();
+                    }
+                    2 => {
+                        let index = (value & 0x0F) as usize;
+                        if index < self.registers.len() {
+                            self.registers[index] = low;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        self.control_latch = None;
+        if self.code == 3 {
+            self.write_cram(value);
+        } else {
+            self.vram[self.address as usize] = value;
+        }
+        self.read_buffer = value;
+        self.increment_address();
+    }
+
+    pub fn read_data(&mut self) -> u8 {
+        self.control_latch = None;
+        let value = self.read_buffer;
+        self.read_buffer = self.vram[self.address as usize];
+        self.increment_address();
+        value
+    }
+
+    pub fn read_status(&mut self) -> u8 {
+        let value = self.status;
+        self.status &= !(STATUS_FRAME_INTERRUPT | STATUS_SPRITE_OVERFLOW | STATUS_SPRITE_COLLISION);
+        self.line_interrupt_pending = false;
+        self.control_latch = None;
+        value
+    }
+
+    // The counter jumps back during vertical blanking so that it fits in a byte.
+    pub fn read_v_counter(&self) -> u8 {
+        let jump = match (self.video_standard, self.active_height()) {
+            (VideoStandard::Ntsc, 192) => 0xDA,
+            (VideoStandard::Ntsc, 224) => 0xEA,
+            (VideoStandard::Ntsc, _) => 0xFFFF,
+            (VideoStandard::Pal, 192) => 0xF2,
+            (VideoStandard::Pal, 224) => 0x102,
+            (VideoStandard::Pal, _) => 0x10A,
+        };
+        let lines = self.video_standard.lines_per_frame();
+        if self.line > jump {
+            (self.line - (lines - 256)) as u8
+        } else {
+            self.line as u8
+        }
+    }
+
+    // Port 0x7F returns the H counter as last latched through a TH pin.
+    pub fn read_h_counter(&self) -> u8 {
+        self.h_counter
+    }
+
+    pub fn latch_h_counter(&mut self, pixel: u64) {
+        self.h_counter = h_counter_at(pixel);
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        let frame = self.status & STATUS_FRAME_INTERRUPT != 0 && self.registers[1] & 0x20 != 0;
+        let line = self.line_interrupt_pending && self.registers[0] & 0x10 != 0;
+        frame || line
+    }
+
+    pub fn active_height(&self) -> usize {
+        let m1 = self.registers[1] & 0x10 != 0;
+        let m2 = self.registers[0] & 0x02 != 0;
+        let m3 = self.registers[1] & 0x08 != 0;
+        let m4 = self.registers[0] & 0x04 != 0;
+        if !self.model.supports_extended_height() || !m4 || !m2 {
+            return 192;
+        }
+        match (m1, m3) {
+            (true, false) => 224,
+            (false, true) => 240,
+            _ => 192,
+        }
+    }
+
+    pub fn viewport(&self) -> Viewport {
+        if self.model == Model::GameGear && !self.compatibility_mode {
+            Viewport {
+                x: 48,
+                y: 24,
+                width: 160,
+                height: 144,
+            }
+        } else {
+            Viewport {
+                x: 0,
+                y: 0,
+                width: SCREEN_WIDTH,
+                height: self.active_height(),
+            }
+        }
+    }
+
+    // The most recently drawn colour at (x, y) in full 256-pixel-wide screen coordinates.
+    pub fn screen_pixel(&self, x: usize, y: usize) -> u32 {
+        self.screen[y * SCREEN_WIDTH + x]
+    }
+
+    pub fn frame(&self) -> Frame {
+        let viewport = self.viewport();
+        let mut frame = Frame::new(viewport.width, viewport.height);
+        for y in 0..viewport.height {
+            let source = (viewport.y + y) * SCREEN_WIDTH + viewport.x;
+            let dest = y * viewport.width;
+            frame.pixels[dest..dest + viewport.width]
+                .copy_from_slice(&self.screen[source..source + viewport.width]);
+        }
+        frame
+    }
+
+    // Runs one scanline and returns true once the last line of the frame is done.
+    pub fn step_line(&mut self) -> bool {
+        let height = self.active_height() as u16;
+        if self.line < height {
+            let line = self.line as usize;
+            self.render_line(line);
+        }
+
+        let mut line_interrupt = false;
+        if self.line <= height {
+            if self.line_counter == 0 {
+                self.line_counter = self.registers[10];
+                self.line_interrupt_pending = true;
+                line_interrupt = true;
+            } else {
+                self.line_counter -= 1;
+            }
+        } else {
+            self.line_counter = self.registers[10];
+        }
+
+        let frame_interrupt = self.line == height;
+        if frame_interrupt {
+            self.status |= STATUS_FRAME_INTERRUPT;
+        }
+
+        if let Some(mut hook) = self.scanline_hook.take() {
+            let pixels = if self.line < height {
+                let start = self.line as usize * SCREEN_WIDTH;
+                &self.screen[start..start + SCREEN_WIDTH]
+            } else {
+                &[]
+            };
+            hook(&ScanlineEvent {
+                line: self.line,
+                pixels,
+                registers: self.registers,
+                line_interrupt,
+                frame_interrupt,
+            });
+            self.scanline_hook = Some(hook);
+        }
+
+        self.line += 1;
+        if self.line >= self.video_standard.lines_per_frame() {
+            self.line = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn increment_address(&mut self) {
+        self.address = (self.address + 1) & 0x3FFF;
+    }
+
+    fn write_cram(&mut self, value: u8) {
+        match self.model {
+            Model::MasterSystem1 | Model::MasterSystem2 => {
+                self.cram[(self.address & 0x1F) as usize] = value & 0x3F
+            }
+            Model::GameGear if self.compatibility_mode => {
+                let index = (self.address & 0x1F) as usize * 2;
+                let (high, low) = alu::get_octets(palette::sms_to_game_gear(value));
+                self.cram[index] = low;
+                self.cram[index + 1] = high;
+            }
+            Model::GameGear => {
+                // Even addresses only latch; the odd write commits both bytes.
+                let index = (self.address & 0x3F) as usize;
+                if index & 1 == 0 {
+                    self.cram_latch = value;
+                } else {
+                    self.cram[index - 1] = self.cram_latch;
+                    self.cram[index] = value & 0x0F;
+                }
+            }
+        }
+    }
+
+    pub(crate) fn colour(&self, index: usize) -> u32 {
+        match self.model {
+            Model::MasterSystem1 | Model::MasterSystem2 => palette::from_sms(self.cram[index]),
+            Model::GameGear => palette::from_game_gear(alu::get_word(
+                self.cram[index * 2 + 1],
+                self.cram[index * 2],
+            )),
+        }
+    }
+
+    fn name_table_base(&self) -> usize {
+        if self.active_height() == 192 {
+            (self.registers[2] as usize & 0x0E) << 10
+        } else {
+            ((self.registers[2] as usize & 0x0C) << 10) | 0x0700
+        }
+    }
+
+    fn name_table_mask(&self) -> usize {
+        if self.model.masks_name_table() && self.registers[2] & 0x01 == 0 {
+            !0x0400
+        } else {
+            !0
+        }
+    }
+
+    pub(crate) fn name_table_rows(&self) -> usize {
+        if self.active_height() == 192 {
+            28
+        } else {
+            32
+        }
+    }
+
+    pub(crate) fn name_table_entry(&self, column: usize, row: usize) -> u16 {
+        let address = (self.name_table_base() + row * 64 + column * 2) & self.name_table_mask();
+        alu::get_word(self.vram[address + 1], self.vram[address])
+    }
+
+    // Palette index of a pixel inside the tile referenced by a name table entry.
+    pub(crate) fn entry_pixel(&self, entry: u16, column: usize, row: usize) -> u8 {
+        let pattern = (entry & 0x01FF) as usize;
+        let row = if entry & 0x0400 != 0 { 7 - row } else { row };
+        let column = if entry & 0x0200 != 0 {
+            7 - column
+        } else {
+            column
+        };
+        let palette = if entry & 0x0800 != 0 { 16 } else { 0 };
+        palette + self.tile_pixel(pattern, row, column)
+    }
+
+    pub(crate) fn sprite_table_base(&self) -> usize {
+        (self.registers[5] as usize & 0x7E) << 7
+    }
+
+    pub(crate) fn tile_pixel(&self, pattern: usize, row: usize, column: usize) -> u8 {
+        let address = (pattern * 32 + row * 4) & 0x3FFF;
+        let bit = 7 - column;
+        (0..4).fold(0, |acc, plane| {
+            acc | (((self.vram[address + plane] >> bit) & 1) << plane)
+        })
+    }
+
+    fn render_line(&mut self, y: usize) {
+        let backdrop = 16 + (self.registers[7] & 0x0F) as usize;
+        let start = y * SCREEN_WIDTH;
+
+        if self.registers[1] & 0x40 == 0 {
+            let colour = self.colour(backdrop);
+            for pixel in &mut self.screen[start..start + SCREEN_WIDTH] {
+                *pixel = colour;
+            }
+            return;
+        }
+
+        let mut background = [0u8; SCREEN_WIDTH];
+        let mut priority = [false; SCREEN_WIDTH];
+        let mut sprites = [0u8; SCREEN_WIDTH];
+        self.render_background(y, &mut background, &mut priority);
+        self.render_sprites(y, &mut sprites);
+
+        let mask_first_column = self.registers[0] & 0x20 != 0;
+        for x in 0..SCREEN_WIDTH {
+            let index = if mask_first_column && x < 8 {
+                backdrop
+            } else if sprites[x] != 0 && !priority[x] {
+                16 + sprites[x] as usize
+            } else {
+                background[x] as usize
+            };
+            self.screen[start + x] = self.colour(index);
+        }
+    }
+
+    fn render_background(&self, y: usize, colours: &mut [u8], priority: &mut [bool]) {
+        let lock_top_rows = self.registers[0] & 0x40 != 0 && y < 16;
+        let lock_right_columns = self.registers[0] & 0x80 != 0;
+        let h_scroll = if lock_top_rows { 0 } else { self.registers[8] };
+        let rows = self.name_table_rows() * 8;
+
+        for x in 0..SCREEN_WIDTH {
+            let v_scroll = if lock_right_columns && x >= 192 {
+                0
+            } else {
+                self.registers[9] as usize
+            };
+            let background_y = (y + v_scroll) % rows;
+            let background_x = (x as u8).wrapping_sub(h_scroll) as usize;
+
+            let entry = self.name_table_entry(background_x / 8, background_y / 8);
+            let colour = self.entry_pixel(entry, background_x % 8, background_y % 8);
+            colours[x] = colour;
+            priority[x] = entry & 0x1000 != 0 && colour & 0x0F != 0;
+        }
+    }
+
+    fn render_sprites(&mut self, y: usize, colours: &mut [u8]) {
+        let table = self.sprite_table_base();
+        let height = if self.registers[1] & 0x02 != 0 { 16 } else { 8 };
+        let zoom = if self.registers[1] & 0x01 != 0 { 2 } else { 1 };
+        let shift = if self.registers[0] & 0x08 != 0 { 8 } else { 0 };
+        let high_patterns = if self.registers[6] & 0x04 != 0 {
+            256
+        } else {
+            0
+        };
+        let terminates = self.active_height() == 192;
+        let mut count = 0;
+
+        for i in 0..64 {
+            let sprite_y = self.vram[table + i];
+            if terminates && sprite_y == 0xD0 {
+                break;
+            }
+            let mut top = sprite_y as i32 + 1;
+            if top > 0xF0 {
+                top -= 256;
+            }
+            let line = y as i32 - top;
+            if line < 0 || line >= height * zoom {
+                continue;
+            }
+
+            count += 1;
+            if count > 8 {
+                self.status |= STATUS_SPRITE_OVERFLOW;
+                break;
+            }
+
+            let x = self.vram[table + 0x80 + i * 2] as i32 - shift;
+            let mut pattern = self.vram[table + 0x81 + i * 2] as usize + high_patterns;
+            if height == 16 {
+                pattern &= !1;
+            }
+            let row = (line / zoom) as usize;
+            pattern += row / 8;
+            let width_zoom = if count <= self.model.horizontally_zoomed_sprites() {
+                zoom
+            } else {
+                1
+            };
+
+            for column in 0..8 * width_zoom {
+                let screen_x = x + column;
+                if screen_x < 0 || screen_x >= SCREEN_WIDTH as i32 {
+                    continue;
+                }
+                let colour = self.tile_pixel(pattern, row % 8, (column / width_zoom) as usize);
+                if colour == 0 {
+                    continue;
+                }
+                let pixel = &mut colours[screen_x as usize];
+                if *pixel != 0 {
+                    self.status |= STATUS_SPRITE_COLLISION;
+                } else {
+                    *pixel = colour;
+                }
+            }
+        }
+    }
+
+    // The model is fixed when the VDP is built; the screen is kept for light phasers.
+    pub(crate) fn save_state(&self, out: &mut StateWriter) {
+        out.write_bool(self.video_standard == VideoStandard::Pal);
+        out.write_bool(self.compatibility_mode);
+        out.write_bytes(&self.vram);
+        out.write_bytes(&self.cram);
+        out.write_bytes(&self.registers);
+        out.write_u16(self.address);
+        out.write_u8(self.code);
+        out.write_bool(self.control_latch.is_some());
+        out.write_u8(self.control_latch.unwrap_or(0));
+        out.write_u8(self.cram_latch);
+        out.write_u8(self.read_buffer);
+        out.write_u8(self.status);
+        out.write_u16(self.line);
+        out.write_u8(self.line_counter);
+        out.write_bool(self.line_interrupt_pending);
+        out.write_u8(self.h_counter);
+        for &pixel in &self.screen {
+            out.write_u32(pixel);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, input: &mut StateReader) -> io::Result<()> {
+        self.video_standard = if input.read_bool()? {
+            VideoStandard::Pal
+        } else {
+            VideoStandard::Ntsc
+        };
+        self.compatibility_mode = input.read_bool()?;
+        input.read_bytes(&mut self.vram)?;
+        input.read_bytes(&mut self.cram)?;
+        input.read_bytes(&mut self.registers)?;
+        self.address = input.read_u16()? & 0x3FFF;
+        self.code = input.read_u8()? & 0x03;
+        let latched = input.read_bool()?;
+        let latch = input.read_u8()?;
+        self.control_latch = if latched { Some(latch) } else { None };
+        self.cram_latch = input.read_u8()?;
+        self.read_buffer = input.read_u8()?;
+        self.status = input.read_u8()?;
+        self.line = input.read_u16()? % self.video_standard.lines_per_frame();
+        self.line_counter = input.read_u8()?;
+        self.line_interrupt_pending = input.read_bool()?;
+        self.h_counter = input.read_u8()?;
+        for pixel in &mut self.screen {
+            *pixel = input.read_u32()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn take_scanline_hook_from(&mut self, other: &mut Vdp) {
+        self.scanline_hook = other.scanline_hook.take();
+    }
+
+    pub(crate) fn take_scanline_hook(&mut self) -> Option<ScanlineHook> {
+        self.scanline_hook.take()
+    }
+
+    pub(crate) fn restore_scanline_hook(&mut self, hook: Option<ScanlineHook>) {
+        self.scanline_hook = hook;
+    }
+}
+
+// The pixel within its line that the beam is drawing at a CPU cycle.
+pub fn beam_pixel(cycle: u64) -> u64 {
+    (cycle % CYCLES_PER_LINE) * PIXELS_PER_LINE / CYCLES_PER_LINE
+}
+
+// The counter advances every two pixels and skips from 0x93 to 0xE9 during horizontal blanking.
+pub fn h_counter_at(pixel: u64) -> u8 {
+    let count = (pixel % PIXELS_PER_LINE) / 2;
+    if count > 0x93 {
+        (count + 0xE9 - 0x94) as u8
+    } else {
+        count as u8
+    }
+}